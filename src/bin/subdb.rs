@@ -0,0 +1,322 @@
+//! Command-line tool for looking at and maintaining a SubDB database.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use subdb::{Database, Difference, ExportReader, Inspector, KeyType, Options, SizeScheme};
+
+const USAGE: &str = "\
+Usage: subdb <COMMAND> <PATH> [ARGS]
+
+Commands:
+    info <PATH>                      Show the content tables of each size class and the space
+                                     taken beyond the values
+    stats <PATH>                     Show how full the index is, how far entries are probed and
+                                     how close it is to being reindexed
+    get <PATH> <KEY>                 Print the value of the hex KEY, in hex
+    put <PATH> <KEY> <VALUE>         Add a reference to the hex VALUE under the hex KEY
+    rm <PATH> <KEY>                  Remove a reference to the item with the hex KEY
+    verify <PATH>                    Check the content tables for inconsistencies
+    reindex <PATH> --key-bytes <N> --index-bits <M>
+                                     Rebuild the index with the given geometry
+    compact <PATH>                   Reclaim the space left behind by removed items
+    export <PATH> <FILE>             Write every item to FILE as an export stream
+    import <PATH> <FILE>             Insert every item in the export stream FILE, creating the
+                                     database if need be
+    merge <PATH> <SOURCE>            Insert every item of the database SOURCE, adding up the
+                                     references; an interrupted merge resumes when rerun
+    diff <PATH> <OTHER>              List the items only in PATH (-) or OTHER (+), and those
+                                     whose values (!) or reference counts (~) differ
+    suggest-scheme <PATH> [CLASSES]  Suggest the size scheme of at most CLASSES classes which
+                                     best fits the values stored, and the space it would save
+";
+
+/// A key held in the array `A`, so that databases with keys of any length up to that of the
+/// longest array may be opened.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Key<A>(A);
+
+macro_rules! do_key_lengths {
+	($($n:tt)*) => {
+		$(
+			impl Default for Key<[u8; $n]> {
+				fn default() -> Self {
+					Key([0; $n])
+				}
+			}
+			impl AsRef<[u8]> for Key<[u8; $n]> {
+				fn as_ref(&self) -> &[u8] {
+					&self.0[..]
+				}
+			}
+			impl AsMut<[u8]> for Key<[u8; $n]> {
+				fn as_mut(&mut self) -> &mut [u8] {
+					&mut self.0[..]
+				}
+			}
+		)*
+
+		/// Open the database in `path`, creating it if need be, with keys of `key_length` bytes and
+		/// run `command` on it.
+		fn with_key_length(path: &Path, key_length: usize, command: Command) -> Result<(), String> {
+			match key_length {
+				0 => Err("keys may not be empty".into()),
+				$( $n => run_command::<Key<[u8; $n]>>(path, command), )*
+				_ => Err(format!("keys of {} bytes are longer than this tool supports", key_length)),
+			}
+		}
+	}
+}
+
+do_key_lengths!(
+	1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31 32
+	33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63 64
+);
+
+/// A command which needs the database opened with its key type.
+enum Command {
+	Put(Vec<u8>, Vec<u8>),
+	Remove(Vec<u8>),
+	Reindex(usize, usize),
+	Compact,
+	Import(PathBuf),
+	Merge(PathBuf),
+	Diff(PathBuf),
+}
+
+fn main() {
+	pretty_env_logger::init();
+	let args = std::env::args().skip(1).collect::<Vec<_>>();
+	if let Err(e) = run(&args) {
+		eprintln!("subdb: {}", e);
+		std::process::exit(1);
+	}
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+	let (command, path, rest) = match args {
+		[command, path, rest @ ..] => (command.as_str(), Path::new(path), rest),
+		_ => return Err(format!("missing arguments\n\n{}", USAGE)),
+	};
+	let arg = |i: usize, what: &str| rest.get(i).ok_or_else(|| format!("missing {}\n\n{}", what, USAGE));
+	let hex_arg = |i: usize, what: &str| arg(i, what)
+		.and_then(|a| hex::decode(a.trim_start_matches("0x")).map_err(|e| format!("bad {}: {}", what, e)));
+	match command {
+		"info" => info(path),
+		"stats" => stats(path),
+		"verify" => verify(path),
+		"get" => get(path, &hex_arg(0, "key")?),
+		"export" => export(path, Path::new(arg(0, "file")?)),
+		"suggest-scheme" => {
+			let classes = rest.first().map_or(Ok(63), |c| c.parse::<usize>().map_err(|e| format!("bad classes: {}", e)))?;
+			suggest_scheme(path, classes)
+		}
+		"put" => with_database(path, Command::Put(hex_arg(0, "key")?, hex_arg(1, "value")?)),
+		"rm" => with_database(path, Command::Remove(hex_arg(0, "key")?)),
+		"reindex" => {
+			let flag = |name: &str| rest.iter()
+				.position(|a| a == name)
+				.and_then(|i| rest.get(i + 1))
+				.ok_or_else(|| format!("missing {}\n\n{}", name, USAGE))
+				.and_then(|v| v.parse::<usize>().map_err(|e| format!("bad {}: {}", name, e)));
+			with_database(path, Command::Reindex(flag("--key-bytes")?, flag("--index-bits")?))
+		}
+		"compact" => with_database(path, Command::Compact),
+		"import" => {
+			let file = PathBuf::from(arg(0, "file")?);
+			// A new database takes its key length from the stream.
+			let key_length = if path.join("metadata.subdb").exists() {
+				inspect(path)?.key_length()
+			} else {
+				ExportReader::new(BufReader::new(File::open(&file).map_err(|e| e.to_string())?))
+					.map_err(|e| format!("cannot read {}: {}", file.display(), e))?
+					.key_length()
+			};
+			with_key_length(path, key_length, Command::Import(file))
+		}
+		"merge" => {
+			let source = PathBuf::from(arg(0, "source")?);
+			if !source.join("metadata.subdb").is_file() {
+				return Err(format!("{} is not a database", source.display()))
+			}
+			with_database(path, Command::Merge(source))
+		}
+		"diff" => {
+			let other = PathBuf::from(arg(0, "other")?);
+			if !other.join("metadata.subdb").is_file() {
+				return Err(format!("{} is not a database", other.display()))
+			}
+			with_database(path, Command::Diff(other))
+		}
+		_ => Err(format!("unknown command {}\n\n{}", command, USAGE)),
+	}
+}
+
+fn inspect(path: &Path) -> Result<Inspector, String> {
+	Inspector::open(path).map_err(|e| format!("cannot open {}: {}", path.display(), e))
+}
+
+fn info(path: &Path) -> Result<(), String> {
+	let inspector = inspect(path)?;
+	let scheme = inspector.size_scheme();
+	let mut classes = BTreeMap::new();
+	for table in inspector.tables() {
+		let class = classes.entry(table.size_class).or_insert((0, 0, 0, 0));
+		class.0 += 1;
+		class.1 += table.used as usize;
+		class.2 += table.total as usize;
+		class.3 += table.bytes_used;
+	}
+	println!("{:>5} {:>8} {:>7} {:>12} {:>12} {:>14}",
+		"class", "size", "tables", "items", "capacity", "bytes used");
+	for (class, (tables, used, capacity, bytes_used)) in classes {
+		let size = scheme.size(class.into()).map_or("oversize".into(), |s| s.to_string());
+		println!("{:>5} {:>8} {:>7} {:>12} {:>12} {:>14}",
+			class, size, tables, used, capacity, bytes_used);
+	}
+	let stats = inspector.stats();
+	let padding = stats.classes.iter().map(|c| c.padding_bytes).sum::<u64>();
+	println!();
+	println!("Items:          {}", stats.items());
+	println!("Value bytes:    {}", stats.value_bytes());
+	println!("Padding bytes:  {}", padding);
+	println!("Overhead:       {:.1} bytes per item", stats.overhead_per_item());
+	Ok(())
+}
+
+fn verify(path: &Path) -> Result<(), String> {
+	let problems = inspect(path)?.verify();
+	for problem in &problems {
+		println!("{}", problem);
+	}
+	if !problems.is_empty() {
+		return Err(format!("{} problems found", problems.len()))
+	}
+	eprintln!("No problems found");
+	Ok(())
+}
+
+fn stats(path: &Path) -> Result<(), String> {
+	let inspector = inspect(path)?;
+	let stats = inspector.index_stats();
+	println!("Key length:     {} bytes", inspector.key_length());
+	println!("Index:          {} bits, {} key bytes, {} slots", stats.index_bits, stats.key_bytes, stats.slots);
+	println!("Entries:        {} ({:.1}% load)", stats.occupied, stats.load_factor() * 100.0);
+	println!("Mean probe:     {:.3}", stats.mean_key_correction());
+	println!("Longest probe:  {} (reindex at {})",
+		stats.key_corrections.last().map_or(0, |&(c, _)| c), stats.triggers.1);
+	println!("Most skipped:   {} (reindex above {})", stats.max_skipped_count, stats.triggers.0);
+	println!("Longest run:    {} slots", stats.longest_cluster);
+	println!("Probe lengths:");
+	for (probe, count) in stats.key_corrections {
+		println!("    {:>6}: {}", probe, count);
+	}
+	println!("Skipped counts:");
+	for (skipped, count) in stats.skipped_counts {
+		println!("    {:>6}: {}", skipped, count);
+	}
+	Ok(())
+}
+
+fn get(path: &Path, key: &[u8]) -> Result<(), String> {
+	let inspector = inspect(path)?;
+	if key.len() != inspector.key_length() {
+		return Err(format!("keys are {} bytes, not {}", inspector.key_length(), key.len()))
+	}
+	let item = inspector.get(key).ok_or("key not found")?;
+	eprintln!("{} references", item.ref_count);
+	println!("{}", hex::encode(item.value));
+	Ok(())
+}
+
+fn export(path: &Path, file: &Path) -> Result<(), String> {
+	let inspector = inspect(path)?;
+	let out = BufWriter::new(File::create(file).map_err(|e| e.to_string())?);
+	let count = inspector.export(out).map_err(|e| e.to_string())?;
+	eprintln!("Exported {} items", count);
+	Ok(())
+}
+
+fn suggest_scheme(path: &Path, classes: usize) -> Result<(), String> {
+	let inspector = inspect(path)?;
+	let histogram = inspector.value_sizes();
+	let current = inspector.size_scheme();
+	// Each class in use has, on average, half of a table standing empty.
+	let class_overhead = current.table_bytes() / 2;
+	let scheme = SizeScheme::optimal(&histogram, current.max_size(), classes, class_overhead);
+	let items: u64 = histogram.iter().map(|&(_, count)| count as u64).sum();
+	let values: u64 = histogram.iter().map(|&(size, count)| size as u64 * count as u64).sum();
+	let before = current.footprint(&histogram, class_overhead) as u64;
+	let after = scheme.footprint(&histogram, class_overhead) as u64;
+
+	println!("Sizes: {:?}", scheme.sizes().collect::<Vec<_>>());
+	println!("{} items of {} bytes in total", items, values);
+	println!("Current scheme:   {} bytes ({} padding)", before, before - values.min(before));
+	println!("Suggested scheme: {} bytes ({} padding)", after, after - values.min(after));
+	if before > 0 {
+		println!("Saving: {:.1}%", (before as f64 - after as f64) * 100.0 / before as f64);
+	}
+	Ok(())
+}
+
+/// Open the database in `path`, which must exist, with its key type and run `command` on it.
+fn with_database(path: &Path, command: Command) -> Result<(), String> {
+	with_key_length(path, inspect(path)?.key_length(), command)
+}
+
+fn key_of<K: KeyType>(key: &[u8]) -> Result<K, String> {
+	let mut k = K::default();
+	if key.len() != k.as_ref().len() {
+		return Err(format!("keys are {} bytes, not {}", k.as_ref().len(), key.len()))
+	}
+	k.as_mut().copy_from_slice(key);
+	Ok(k)
+}
+
+fn run_command<K: KeyType>(path: &Path, command: Command) -> Result<(), String> {
+	let mut db: Database<K> = Options::from_path(path.into())
+		.open()
+		.map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+	match command {
+		Command::Put(key, value) => {
+			let rc = db.try_insert(&value, &key_of::<K>(&key)?).map_err(|e| e.to_string())?;
+			eprintln!("{} references", rc);
+		}
+		Command::Remove(key) => {
+			let rc = db.remove(&key_of::<K>(&key)?).map_err(|_| "key not found")?;
+			eprintln!("{} references", rc);
+		}
+		Command::Reindex(key_bytes, index_bits) => {
+			db.reindex(key_bytes, index_bits).map_err(|e| e.to_string())?;
+		}
+		Command::Compact => {
+			eprintln!("Reclaimed {} bytes", db.compact());
+		}
+		Command::Import(file) => {
+			let input = BufReader::new(File::open(&file).map_err(|e| e.to_string())?);
+			let count = db.import(input).map_err(|e| format!("cannot import {}: {}", file.display(), e))?;
+			eprintln!("Imported {} items", count);
+		}
+		Command::Merge(source_path) => {
+			let count = db.merge_from(&source_path, |p| eprintln!("Merged {} of {} items", p.merged, p.total))
+				.map_err(|e| format!("cannot merge {}: {}", source_path.display(), e))?;
+			eprintln!("Merged {} items", count);
+		}
+		Command::Diff(other_path) => {
+			let other = inspect(&other_path)?;
+			let count = db.diff(&other, |difference| match difference {
+				Difference::OnlyInSelf(key) => println!("- {}", hex::encode(key)),
+				Difference::OnlyInOther(key) => println!("+ {}", hex::encode(key)),
+				Difference::ValueDiffers(key) => println!("! {} value differs", hex::encode(key)),
+				Difference::RefCountDiffers(key, ours, theirs) =>
+					println!("~ {} references {} vs {}", hex::encode(key), ours, theirs),
+			}).map_err(|e| format!("cannot diff {}: {}", other_path.display(), e))?;
+			if count > 0 {
+				return Err(format!("{} differences found", count))
+			}
+			eprintln!("No differences found");
+		}
+	}
+	Ok(())
+}
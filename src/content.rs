@@ -1,15 +1,70 @@
-use std::path::PathBuf;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::{Path, PathBuf};
+use log::warn;
 use parking_lot::MappedRwLockReadGuard;
 
-use crate::datum_size::DatumSize;
+use crate::datum_size::{DatumSize, SizeScheme};
 use crate::types::{KeyType, EntryIndex, TableIndex};
-use crate::content_address::ContentAddress;
+use crate::content_address::{ContentAddress, CompactContentAddress};
+use crate::freemap::FreeMap;
 use crate::table::{Table, TableItemIndex, RefCount, TableItemCount};
+use crate::stats::ClassStats;
 use crate::Error;
 
+/// Whether `name` is the name of a content table file or an oversize item file.
+fn is_content_file(name: &str) -> bool {
+	let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+	let mut parts = name.splitn(2, '-');
+	let size_class = parts.next().unwrap_or("");
+	let mut rest = parts.next().unwrap_or("").splitn(2, '.');
+	let (table_index, suffix) = (rest.next().unwrap_or(""), rest.next());
+	is_number(size_class) && is_number(table_index) && matches!(suffix, Some(s) if s == "content" || is_number(s))
+}
+
+/// The content table and oversize item files in `path`.
+fn content_files(path: &Path) -> Result<Vec<PathBuf>, Error> {
+	let mut files = Vec::new();
+	for entry in std::fs::read_dir(path)? {
+		let entry = entry?;
+		if entry.file_type()?.is_file() && matches!(entry.file_name().to_str(), Some(name) if is_content_file(name)) {
+			files.push(entry.path());
+		}
+	}
+	Ok(files)
+}
+
+/// Delete all content table and oversize item files in `path`.
+pub fn remove_content_files(path: &Path) -> Result<(), Error> {
+	for file in content_files(path)? {
+		std::fs::remove_file(file)?;
+	}
+	Ok(())
+}
+
+/// Move all content table and oversize item files in `from` into `to`.
+pub fn move_content_files(from: &Path, to: &Path) -> Result<(), Error> {
+	for file in content_files(from)? {
+		std::fs::rename(&file, to.join(file.file_name().expect("Listed files have names; qed")))?;
+	}
+	Ok(())
+}
+
 pub struct Content<K: KeyType> {
 	path: PathBuf,
+	oversize_path: PathBuf,
+	scheme: SizeScheme,
 	tables: Vec<Vec<Table<K>>>,
+	/// For each size class, the tables other than the preferred one which are not full, fullest
+	/// (and then lowest) first. Each is there once, along with how many items it held when it was
+	/// queued; queued tables only ever lose items, so this is never less than they hold now.
+	queue: Vec<BinaryHeap<(TableItemCount, Reverse<TableIndex>)>>,
+	/// For each size class, which of its tables are in `queue`.
+	queued: Vec<FreeMap>,
+	/// For each size class, the table into which we're currently allocating. Always one which is
+	/// not full, and we try to keep it the fullest such table to minimise fragmentation. If `None`
+	/// then we'll pick one on the next allocation.
+	preferred: Vec<Option<TableIndex>>,
 	min_items_backed: TableItemCount,
 	trigger_oversize_mapped: usize,
 	shrink_oversize_mapped: usize,
@@ -17,13 +72,72 @@ pub struct Content<K: KeyType> {
 }
 
 impl<K: KeyType> Content<K> {
-	/// Creates a new content table of `datum_size`.
-	fn new_table(&mut self, datum_size: DatumSize) -> (TableIndex, &mut Table<K>) {
+	/// Creates a new content table of `datum_size`, unless the size class already has as many
+	/// tables as can be addressed.
+	fn new_table(&mut self, datum_size: DatumSize) -> Result<(TableIndex, &mut Table<K>), Error> {
 		let s = <u8>::from(datum_size);
 		let table_index = self.tables[s as usize].len();
+		let max_tables = self.scheme.max_tables(datum_size);
+		if table_index >= max_tables {
+			return Err(Error::AddressSpaceFull)
+		}
+		if (table_index + 1) * 10 > max_tables * 9 {
+			warn!(target: "content", "Size class {:?} has used {} of its {} tables", datum_size, table_index + 1, max_tables);
+		}
 		let table_path = self.table_path(s, table_index);
-		self.tables[s as usize].push(Table::open(table_path, datum_size, self.min_items_backed));
-		(table_index, &mut self.tables[s as usize][table_index])
+		self.tables[s as usize].push(Table::open(table_path, datum_size, &self.scheme, self.min_items_backed)?);
+		self.queued[s as usize].reserve(table_index + 1);
+		Ok((table_index, &mut self.tables[s as usize][table_index]))
+	}
+
+	/// Queue every table of size class `s` which is not full and forget the preferred table.
+	fn refresh_queue(&mut self, s: usize) {
+		let tables = &self.tables[s];
+		let mut queued = FreeMap::with_capacity(tables.len());
+		self.queue[s] = tables.iter()
+			.enumerate()
+			.filter(|(_, table)| table.available() > 0)
+			.inspect(|&(t, _)| queued.set(t))
+			.map(|(t, table)| (table.used(), Reverse(t)))
+			.collect();
+		self.queued[s] = queued;
+		self.preferred[s] = None;
+	}
+
+	/// Queue table `content_table` of size class `s`, which must not be full, preferred or queued.
+	fn enqueue(&mut self, s: usize, content_table: TableIndex) {
+		self.queue[s].push((self.tables[s][content_table].used(), Reverse(content_table)));
+		self.queued[s].set(content_table);
+	}
+
+	/// Note that an item has been freed from table `content_table` of size class `s`.
+	fn note_freed(&mut self, s: usize, content_table: TableIndex) {
+		if self.preferred[s] == Some(content_table) || self.queued[s].get(content_table) {
+			return
+		}
+		// It was full until now. If it's fuller than the preferred table then it takes its place.
+		match self.preferred[s] {
+			Some(preferred) if self.tables[s][content_table].used() > self.tables[s][preferred].used() => {
+				self.enqueue(s, preferred);
+				self.preferred[s] = Some(content_table);
+			}
+			_ => self.enqueue(s, content_table),
+		}
+	}
+
+	/// Take the fullest table of size class `s` which is not full from the queue, if there is one.
+	fn fullest_available(&mut self, s: usize) -> Option<TableIndex> {
+		while let Some((used, Reverse(t))) = self.queue[s].pop() {
+			let actual = self.tables[s][t].used();
+			if actual < used {
+				// It has lost items since it was queued, so it goes back in its rightful place.
+				self.queue[s].push((actual, Reverse(t)));
+			} else {
+				self.queued[s].clear(t);
+				return Some(t)
+			}
+		}
+		None
 	}
 
 	/// Generates the file name of a content table with `size_class` and `table_index`.
@@ -31,11 +145,20 @@ impl<K: KeyType> Content<K> {
 		format!("{}-{}.content", size_class, table_index)
 	}
 
+	/// Generates the path for a content table with `size_class` and `table_index`. Sized tables
+	/// live in `path` and oversize tables in `oversize_path`.
+	pub(crate) fn table_path_in(path: &Path, oversize_path: &Path, size_class: u8, table_index: TableIndex) -> PathBuf {
+		let mut table_path = match DatumSize::from(size_class) {
+			DatumSize::Oversize => oversize_path.to_path_buf(),
+			DatumSize::Size(_) => path.to_path_buf(),
+		};
+		table_path.push(Self::table_name(size_class, table_index));
+		table_path
+	}
+
 	/// Generates the path for a content table with `size_class` and `table_index`.
 	fn table_path(&self, size_class: u8, table_index: TableIndex) -> PathBuf {
-		let mut table_path = self.path.clone();
-		table_path.push(&Self::table_name(size_class, table_index));
-		table_path
+		Self::table_path_in(&self.path, &self.oversize_path, size_class, table_index)
 	}
 
 	pub fn commit(&mut self) {
@@ -46,17 +169,32 @@ impl<K: KeyType> Content<K> {
 		}
 	}
 
+	/// The size scheme of our tables.
+	pub fn scheme(&self) -> &SizeScheme {
+		&self.scheme
+	}
+
 	/// Get the raw reference to an item's content value, optionally checking its hash to ensure
 	/// it's the right item.
-	pub fn item_ref(&self, address: &ContentAddress, check_hash: Option<&K>) -> Result<MappedRwLockReadGuard<[u8]>, ()> {
+	pub fn item_ref(&self, address: &CompactContentAddress, check_hash: Option<&K>) -> Result<MappedRwLockReadGuard<[u8]>, ()> {
+		let address = address.expand(&self.scheme);
 		let s = u8::from(address.datum_size) as usize;
 		self.tables[s as usize][address.content_table]
 			.item_ref(address.entry_index as TableItemIndex, check_hash)
 	}
 
+	/// Unmap the value of the item at `address` if it was mapped, once a scan is done with it. See
+	/// `Table::release_mapping`.
+	pub fn release_mapping(&self, address: &CompactContentAddress) {
+		let address = address.expand(&self.scheme);
+		self.tables[u8::from(address.datum_size) as usize][address.content_table]
+			.release_mapping(address.entry_index as TableItemIndex)
+	}
+
 	/// Get the reference count for an item, optionally checking its hash to ensure
 	/// it's the right item.
-	pub fn item_ref_count(&self, address: &ContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+	pub fn item_ref_count(&self, address: &CompactContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+		let address = address.expand(&self.scheme);
 		let s = u8::from(address.datum_size) as usize;
 		self.tables[s as usize][address.content_table]
 			.item_ref_count(address.entry_index as TableItemIndex, check_hash)
@@ -65,29 +203,48 @@ impl<K: KeyType> Content<K> {
 	/// Get the reference count for an item, optionally checking its hash to ensure
 	/// it's the right item.
 	#[allow(dead_code)]
-	pub fn item_hash(&self, address: &ContentAddress) -> Result<K, ()> {
+	pub fn item_hash(&self, address: &CompactContentAddress) -> Result<K, ()> {
+		let address = address.expand(&self.scheme);
 		let s = u8::from(address.datum_size) as usize;
 		self.tables[s as usize][address.content_table]
 			.item_hash(address.entry_index as TableItemIndex)
 	}
 
+	/// Whether there is an item at `address` with the key `key`. Unlike other accessors, this is
+	/// fine to call with an address which might not be valid for this content.
+	pub fn holds(&self, address: &CompactContentAddress, key: &K) -> bool {
+		if let DatumSize::Size(size_class) = address.datum_size() {
+			if size_class as usize >= self.scheme.classes() {
+				return false
+			}
+		}
+		let address = address.expand(&self.scheme);
+		let s = u8::from(address.datum_size) as usize;
+		matches!(self.tables[s].get(address.content_table),
+			Some(t) if t.item_hash(address.entry_index as TableItemIndex).as_ref() == Ok(key))
+	}
+
 	/// Allocate space to store an item's contents and return its content address.
 	///
 	/// - `datum_size` is the size class of the item.
 	/// - `key` is the hash key of the item.
 	/// - `actual_size` is its real size, never more than `datum_size.size()`.
-	fn allocate(&mut self, key: &K, actual_size: usize) -> ContentAddress {
-		let datum_size = DatumSize::nearest(actual_size);
+	fn allocate(&mut self, key: &K, actual_size: usize) -> Result<ContentAddress, Error> {
+		let datum_size = self.scheme.nearest(actual_size);
 		let s = u8::from(datum_size) as usize;
-		for (content_table, table) in self.tables[s as usize].iter_mut().enumerate() {
-			if let Some(entry_index) = table.allocate(key, actual_size) {
-				return ContentAddress { datum_size, content_table, entry_index: entry_index as EntryIndex };
-			}
+		let content_table = match self.preferred[s].or_else(|| self.fullest_available(s)) {
+			Some(content_table) => content_table,
+			// Out of space - create a new table
+			None => self.new_table(datum_size)?.0,
+		};
+		let table = &mut self.tables[s][content_table];
+		let entry_index = table.allocate(key, actual_size).expect("Table is not full; qed");
+		if table.available() == 0 {
+			self.preferred[s] = None;
+		} else {
+			self.preferred[s] = Some(content_table);
 		}
-		// Out of space - would create a new table
-		let (content_table, table) = self.new_table(datum_size);
-		let entry_index = table.allocate(key, actual_size).expect("Freshly created");
-		ContentAddress { datum_size, content_table, entry_index: entry_index as EntryIndex }
+		Ok(ContentAddress { datum_size, content_table, entry_index: entry_index as EntryIndex })
 	}
 
 	/// Allocate space to store an item's contents, fill with data and return its content address.
@@ -95,19 +252,132 @@ impl<K: KeyType> Content<K> {
 	/// - `datum_size` is the size class of the item.
 	/// - `key` is the hash key of the item.
 	/// - `data` is its data, whose length is never more than `datum_size.size()`.
-	pub fn emplace(&mut self, key: &K, data: &[u8]) -> ContentAddress {
-		let address = self.allocate(key, data.len());
+	pub fn emplace(&mut self, key: &K, data: &[u8]) -> Result<CompactContentAddress, Error> {
+		let address = self.allocate(key, data.len())?;
 		let s = u8::from(address.datum_size) as usize;
+		let compact = match address.compact(&self.scheme) {
+			Ok(compact) => compact,
+			Err(e) => {
+				self.tables[s][address.content_table]
+					.release(address.entry_index as TableItemIndex)
+					.expect("Item was just allocated; qed");
+				self.note_freed(s, address.content_table);
+				return Err(e)
+			}
+		};
 		self.tables[s as usize][address.content_table]
 			.set_item(address.entry_index as TableItemIndex, data)
 			.expect("Unexpected error setting item");
 		self.idle();
-		address
+		Ok(compact)
+	}
+
+	/// Store an item which has `ref_count` references and return its content address.
+	pub fn restore(&mut self, key: &K, data: &[u8], ref_count: RefCount) -> Result<CompactContentAddress, Error> {
+		let compact = self.emplace(key, data)?;
+		let address = compact.expand(&self.scheme);
+		let s = u8::from(address.datum_size) as usize;
+		self.tables[s][address.content_table]
+			.set_ref_count(address.entry_index as TableItemIndex, ref_count)
+			.expect("Item was just allocated; qed");
+		Ok(compact)
+	}
+
+	/// Call `f` with the key and address of every item.
+	pub fn for_each(&self, mut f: impl FnMut(&K, &CompactContentAddress)) {
+		for (s, tables) in self.tables.iter().enumerate() {
+			let datum_size = DatumSize::from(s as u8);
+			for (content_table, table) in tables.iter().enumerate() {
+				for i in table.allocated() {
+					let key = table.item_hash(i).expect("Item is allocated. Database corrupt?");
+					let address = ContentAddress { datum_size, content_table, entry_index: i as EntryIndex };
+					f(&key, &address.compact(&self.scheme).expect("Item is allocated. Database corrupt?"));
+				}
+			}
+		}
+	}
+
+	/// How many times an item has been freed from or moved into each table of size class
+	/// `datum_size`. See `Table::changes`.
+	pub fn table_changes(&self, datum_size: DatumSize) -> Vec<u64> {
+		self.tables.get(u8::from(datum_size) as usize)
+			.map_or_else(Vec::new, |tables| tables.iter().map(|table| table.changes()).collect())
+	}
+
+	/// The size class and index of every table.
+	pub fn table_ids(&self) -> Vec<(DatumSize, TableIndex)> {
+		self.tables.iter()
+			.enumerate()
+			.flat_map(|(s, tables)| (0..tables.len()).map(move |t| (DatumSize::from(s as u8), t)))
+			.collect()
+	}
+
+	/// Call `f` with the key, reference count and value of every item in table `content_table` of
+	/// size class `datum_size`, in the order in which they're stored.
+	pub fn for_each_in_table(&self, datum_size: DatumSize, content_table: TableIndex, mut f: impl FnMut(&K, RefCount, &[u8])) {
+		let table = &self.tables[u8::from(datum_size) as usize][content_table];
+		for i in table.allocated() {
+			let key = table.item_hash(i).expect("Item is allocated. Database corrupt?");
+			let ref_count = table.item_ref_count(i, None).expect("Item is allocated. Database corrupt?");
+			let value = table.item_ref(i, None).expect("Item is allocated. Database corrupt?");
+			f(&key, ref_count, &value);
+			drop(value);
+			table.release_mapping(i);
+		}
+	}
+
+	/// The address of the first item at or after `from` in the order in which items are stored,
+	/// looking no further than the tables of size class `last`.
+	pub fn next_item(&self, from: &ContentAddress, last: DatumSize) -> Option<ContentAddress> {
+		let mut s = u8::from(from.datum_size) as usize;
+		let mut content_table = from.content_table;
+		let mut entry_index = from.entry_index;
+		while s <= u8::from(last) as usize {
+			if let Some(table) = self.tables[s].get(content_table) {
+				if let Some(i) = table.next_allocated(entry_index as TableItemIndex) {
+					return Some(ContentAddress { datum_size: DatumSize::from(s as u8), content_table, entry_index: i as EntryIndex })
+				}
+				content_table += 1;
+			} else {
+				s += 1;
+				content_table = 0;
+			}
+			entry_index = 0;
+		}
+		None
+	}
+
+	/// Remove every item, calling `f` with the key, reference count, value and address of each
+	/// just before it goes. Each table is committed once it is empty.
+	pub fn drain(&mut self, mut f: impl FnMut(&K, RefCount, &[u8], &CompactContentAddress)) {
+		let scheme = &self.scheme;
+		for (s, tables) in self.tables.iter_mut().enumerate() {
+			let datum_size = DatumSize::from(s as u8);
+			for (content_table, table) in tables.iter_mut().enumerate() {
+				for i in table.allocated() {
+					let key = table.item_hash(i).expect("Item is allocated. Database corrupt?");
+					let ref_count = table.item_ref_count(i, None).expect("Item is allocated. Database corrupt?");
+					let address = ContentAddress { datum_size, content_table, entry_index: i as EntryIndex };
+					f(
+						&key,
+						ref_count,
+						&table.item_ref(i, None).expect("Item is allocated. Database corrupt?"),
+						&address.compact(scheme).expect("Item is allocated. Database corrupt?"),
+					);
+					table.release(i).expect("Item is allocated; qed");
+				}
+				table.commit();
+			}
+		}
+		for s in 0..self.tables.len() {
+			self.refresh_queue(s);
+		}
 	}
 
 	/// Increment the references for an item given its content `address` and optionally checking
 	/// that its key hash is the expected `check_hash`.
-	pub fn bump(&mut self, address: &ContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+	pub fn bump(&mut self, address: &CompactContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+		let address = address.expand(&self.scheme);
 		let s = u8::from(address.datum_size) as usize;
 		let r = self.tables[s as usize][address.content_table]
 			.bump(address.entry_index as TableItemIndex, check_hash);
@@ -115,17 +385,42 @@ impl<K: KeyType> Content<K> {
 		r
 	}
 
+	/// Set the number of references of an item given its content `address`, optionally checking
+	/// that its key hash is the expected `check_hash`.
+	pub fn set_ref_count(&mut self, address: &CompactContentAddress, check_hash: Option<&K>, ref_count: RefCount) -> Result<(), ()> {
+		if matches!(check_hash, Some(hash) if self.item_hash(address).as_ref() != Ok(hash)) {
+			return Err(())
+		}
+		let address = address.expand(&self.scheme);
+		let s = u8::from(address.datum_size) as usize;
+		self.tables[s][address.content_table].set_ref_count(address.entry_index as TableItemIndex, ref_count)
+	}
+
 	/// Decrement the references for an item given its content `address` and optionally checking
 	/// that its key hash is the expected `check_hash`. If they are decremented to zero then the
 	/// storage used for the item will be freed.
-	pub fn free(&mut self, address: &ContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+	pub fn free(&mut self, address: &CompactContentAddress, check_hash: Option<&K>) -> Result<RefCount, ()> {
+		let address = address.expand(&self.scheme);
 		let s = u8::from(address.datum_size) as usize;
 		let r = self.tables[s as usize][address.content_table]
 			.free(address.entry_index as TableItemIndex, check_hash);
+		if r == Ok(0) {
+			self.note_freed(s, address.content_table);
+		}
 		self.idle();
 		r
 	}
 
+	/// Free the storage used for an item given its content `address`, regardless of how many
+	/// references it has.
+	pub fn release(&mut self, address: &CompactContentAddress) -> Result<(), ()> {
+		let address = address.expand(&self.scheme);
+		let s = u8::from(address.datum_size) as usize;
+		self.tables[s][address.content_table].release(address.entry_index as TableItemIndex)?;
+		self.note_freed(s, address.content_table);
+		Ok(())
+	}
+
 	/// Reduce the amount we have mapped in our oversize tables, if above the trigger amount.
 	pub fn idle(&mut self) {
 		for t in self.tables[63].iter_mut() {
@@ -133,35 +428,167 @@ impl<K: KeyType> Content<K> {
 		}
 	}
 
+	/// Pack the live items of each size class into as few tables, and each table into as few
+	/// slots, as possible, then shrink the table files to fit and delete any empty tables at the
+	/// end of a size class. `relocated` is called with the key, old address and new address of
+	/// every item moved.
+	///
+	/// Empty tables which are followed by non-empty tables can't be deleted since that would change
+	/// the addresses of their successors, so they are only shrunk.
+	pub fn compact(&mut self, mut relocated: impl FnMut(&K, &CompactContentAddress, &CompactContentAddress)) {
+		let min_items_backed = self.min_items_backed;
+		let scheme = &self.scheme;
+		for (s, tables) in self.tables.iter_mut().enumerate() {
+			let datum_size = DatumSize::from(s as u8);
+			if datum_size == DatumSize::Oversize {
+				// Each oversize item is its own file; there's nothing to be gained by moving them.
+			} else {
+				// Densest tables first; we move items out of the sparsest and into the densest.
+				let mut order = (0..tables.len()).collect::<Vec<_>>();
+				order.sort_by_key(|&t| std::cmp::Reverse(tables[t].used()));
+				let mut dest = 0;
+				'sources: for &from in order.iter().rev() {
+					for i in tables[from].allocated() {
+						while dest < order.len() && tables[order[dest]].available() == 0 {
+							dest += 1;
+						}
+						if dest >= order.len() || order[dest] == from {
+							// Everything left is in the densest tables already.
+							break 'sources;
+						}
+						let to = order[dest];
+						let raw = tables[from].raw_item(i);
+						let (j, key) = tables[to].allocate_raw(&raw).expect("Table has space; qed");
+						tables[from].release(i).expect("Item was allocated; qed");
+						relocated(
+							&key,
+							&ContentAddress { datum_size, content_table: from, entry_index: i as EntryIndex }.compact(scheme)
+								.expect("Item was allocated. Database corrupt?"),
+							&ContentAddress { datum_size, content_table: to, entry_index: j as EntryIndex }.compact(scheme)
+								.expect("Item was allocated. Database corrupt?"),
+						);
+					}
+				}
+				for (content_table, table) in tables.iter_mut().enumerate() {
+					table.compact(min_items_backed, |key, from, to| relocated(
+						key,
+						&ContentAddress { datum_size, content_table, entry_index: from as EntryIndex }.compact(scheme)
+							.expect("Item was allocated. Database corrupt?"),
+						&ContentAddress { datum_size, content_table, entry_index: to as EntryIndex }.compact(scheme)
+							.expect("Item was allocated. Database corrupt?"),
+					));
+				}
+			}
+			while matches!(tables.last(), Some(t) if t.used() == 0) {
+				tables.pop().expect("last() is Some; qed").delete();
+			}
+		}
+		for s in 0..self.tables.len() {
+			self.refresh_queue(s);
+		}
+	}
+
+	/// Check every table for internal consistency, returning a description of each problem.
+	pub fn check(&self) -> Vec<String> {
+		self.tables.iter().flat_map(|t| t.iter()).flat_map(|t| t.check()).collect()
+	}
+
+	/// How many items there are of each value size, in order of size.
+	pub fn value_sizes(&self) -> Vec<(u32, u32)> {
+		let mut sizes = std::collections::BTreeMap::<u32, u32>::new();
+		for table in self.tables.iter().flat_map(|t| t.iter()) {
+			for i in table.allocated() {
+				let len = table.item_len(i).expect("Allocated item has a size. Database corrupt?");
+				*sizes.entry(len as u32).or_default() += 1;
+			}
+		}
+		sizes.into_iter().collect()
+	}
+
+	/// How the items of each size class which has any tables use their space.
+	pub fn class_stats(&self) -> Vec<ClassStats> {
+		self.tables.iter()
+			.enumerate()
+			.filter(|(_, tables)| !tables.is_empty())
+			.map(|(s, tables)| {
+				let datum_size = DatumSize::from(s as u8);
+				let mut stats = ClassStats {
+					datum_size,
+					value_size: self.scheme.size(datum_size),
+					tables: tables.len(),
+					items: 0,
+					value_bytes: 0,
+					padding_bytes: 0,
+					header_bytes: 0,
+					value_sizes: vec![],
+				};
+				let mut sizes = std::collections::BTreeMap::<u32, u32>::new();
+				for table in tables {
+					for i in table.allocated() {
+						let len = table.item_len(i).expect("Allocated item has a size. Database corrupt?");
+						stats.items += 1;
+						stats.value_bytes += len as u64;
+						stats.padding_bytes += table.value_size().saturating_sub(len) as u64;
+						stats.header_bytes += table.item_header_size() as u64;
+						*sizes.entry(len as u32).or_default() += 1;
+					}
+				}
+				stats.value_sizes = sizes.into_iter().collect();
+				stats
+			})
+			.collect()
+	}
+
+	/// The total amount of bytes stored on disk.
+	pub fn bytes_used(&self) -> usize {
+		self.tables.iter().flat_map(|t| t.iter()).map(|t| t.bytes_used()).sum()
+	}
+
 	pub fn open(
 		path: PathBuf,
+		oversize_path: PathBuf,
+		scheme: SizeScheme,
 		trigger_oversize_mapped: usize,
 		shrink_oversize_mapped: usize,
 		min_items_backed: TableItemCount,
 	) -> Result<Self, Error> {
 		let tables = (0u8..64).map(|size| (0usize..)
-			.map(|table_index| {
-				let mut table_path = path.clone();
-				table_path.push(&Self::table_name(size, table_index));
-				table_path
-			})
+			.map(|table_index| Self::table_path_in(&path, &oversize_path, size, table_index))
 			.take_while(|table_path| table_path.is_file())
-			.map(|table_path| Table::open(table_path, DatumSize::from(size), min_items_backed))
-			.collect()
-		).collect();
+			.map(|table_path| Table::open(table_path, DatumSize::from(size), &scheme, min_items_backed))
+			.collect::<Result<Vec<_>, _>>()
+		).collect::<Result<Vec<_>, _>>()?;
 
-		Ok(Self { path, tables, min_items_backed, trigger_oversize_mapped, shrink_oversize_mapped, _dummy: Default::default() })
+		let mut content = Self {
+			path, oversize_path, scheme, queue: vec![BinaryHeap::new(); tables.len()],
+			queued: vec![FreeMap::default(); tables.len()], preferred: vec![None; tables.len()],
+			tables, min_items_backed, trigger_oversize_mapped, shrink_oversize_mapped, _dummy: Default::default()
+		};
+		for s in 0..content.tables.len() {
+			content.refresh_queue(s);
+		}
+		Ok(content)
 	}
 
-	pub fn info(&self) -> Vec<((DatumSize, usize), (TableItemCount, TableItemCount, usize, usize))> {
+	/// See `Database::info`.
+	pub fn info(&self) -> Vec<((DatumSize, usize), (TableItemCount, TableItemCount, usize, usize), (usize, usize))> {
 		self.tables.iter()
 			.enumerate()
+			.filter(|(_, tables)| !tables.is_empty())
 			.map(|(z, tables)| (DatumSize::from(z as u8), tables))
-			.flat_map(|(datum_size, tables)| tables.iter()
-				.enumerate()
-				.map(|(index, table)| ((datum_size, index), (table.available(), table.used(), table.bytes_used(), table.bytes_mapped())))
-				.collect::<Vec<_>>()
-			)
+			.flat_map(|(datum_size, tables)| {
+				let entries = self.scheme.contents_entries(datum_size);
+				let limit = self.scheme.max_tables(datum_size) * entries;
+				let headroom = limit.saturating_sub(tables.len() * entries);
+				tables.iter()
+					.enumerate()
+					.map(|(index, table)| (
+						(datum_size, index),
+						(table.available(), table.used(), table.bytes_used(), table.bytes_mapped()),
+						(headroom, limit),
+					))
+					.collect::<Vec<_>>()
+			})
 			.collect()
 	}
 }
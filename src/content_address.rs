@@ -1,14 +1,18 @@
 use std::fmt;
-use parity_scale_codec::{self as codec, Encode, Decode};
+use parity_scale_codec::{Encode, Decode};
 use crate::types::{TableIndex, EntryIndex, EncodedSize};
-use crate::datum_size::DatumSize;
+use crate::datum_size::{DatumSize, SizeScheme, ADDRESSES_PER_CLASS};
+use crate::Error;
 
+/// A `ContentAddress` packed into 32 bits: the size class in the low 6 bits and the position of
+/// the item within all tables of that size class in the rest. Unpacking it needs the `SizeScheme`
+/// of the database, since that determines how many items are in each table.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Encode, Decode)]
 pub struct CompactContentAddress(u32);
 
 impl fmt::Debug for CompactContentAddress {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		write!(f, "{:x?} ({:?})", self.0, ContentAddress::from(*self))
+		write!(f, "{:x?} ({:?} @ {})", self.0, self.datum_size(), self.0 >> 6)
 	}
 }
 
@@ -16,6 +20,23 @@ impl EncodedSize for CompactContentAddress {
 	fn encoded_size() -> usize { 4 }
 }
 
+impl CompactContentAddress {
+	/// The size class of the item at this address.
+	pub fn datum_size(self) -> DatumSize {
+		DatumSize::from((self.0 % 64) as u8)
+	}
+
+	/// The full address, given the size `scheme` of the database.
+	pub fn expand(self, scheme: &SizeScheme) -> ContentAddress {
+		let datum_size = self.datum_size();
+		let entries = scheme.contents_entries(datum_size);
+		let rest = (self.0 >> 6) as usize;
+		let content_table = rest / entries;
+		let entry_index = rest % entries;
+		ContentAddress { datum_size, content_table, entry_index }
+	}
+}
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
 pub struct ContentAddress {
 	/// The size, or possibly unsized.
@@ -26,54 +47,39 @@ pub struct ContentAddress {
 	pub entry_index: EntryIndex,
 }
 
-impl<'a> From<&'a ContentAddress> for CompactContentAddress {
-	fn from(x: &'a ContentAddress) -> Self {
-		let a = u8::from(x.datum_size) as u32;
-		let b = (x.entry_index + x.datum_size.contents_entries() * x.content_table) as u32;
-		Self(a | (b << 6))
-	}
-}
-
-impl From<ContentAddress> for CompactContentAddress {
-	fn from(x: ContentAddress) -> Self {
-		From::from(&x)
-	}
-}
-
-impl From<CompactContentAddress> for ContentAddress {
-	fn from(x: CompactContentAddress) -> Self {
-		let datum_size = DatumSize::from((x.0 % 64) as u8);
-		let entries = datum_size.contents_entries();
-		let rest = (x.0 >> 6) as usize;
-		let content_table = rest / entries;
-		let entry_index = rest % entries;
-		Self { datum_size, content_table, entry_index }
-	}
-}
-
-impl EncodedSize for ContentAddress {
-	fn encoded_size() -> usize { 4 }
-}
-
-impl Encode for ContentAddress {
-	fn encode_to<O: codec::Output>(&self, output: &mut O) {
-		CompactContentAddress::from(self).encode_to(output)
-	}
-}
-
-impl Decode for ContentAddress {
-	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
-		Ok(CompactContentAddress::decode(input)?.into())
+impl ContentAddress {
+	/// The compact form of this address, given the size `scheme` of the database. Fails with
+	/// `AddressSpaceFull` if the address is beyond those which a compact address can hold.
+	pub fn compact(&self, scheme: &SizeScheme) -> Result<CompactContentAddress, Error> {
+		let a = u8::from(self.datum_size) as u32;
+		let b = self.entry_index + scheme.contents_entries(self.datum_size) * self.content_table;
+		if b >= ADDRESSES_PER_CLASS {
+			return Err(Error::AddressSpaceFull)
+		}
+		Ok(CompactContentAddress(a | ((b as u32) << 6)))
 	}
 }
 
 #[test]
 fn content_addresses_encode_encode_ok() {
+	let scheme = SizeScheme::default();
 	let a = ContentAddress { datum_size: DatumSize::Size(0), content_table: 1, entry_index: 2 };
-	assert_eq!(a.datum_size.size(), Some(32));
-	assert_eq!(a.datum_size.contents_entries(), 65536);
-	let b = CompactContentAddress::from(&a);
+	assert_eq!(scheme.size(a.datum_size), Some(32));
+	assert_eq!(scheme.contents_entries(a.datum_size), 65536);
+	let b = a.compact(&scheme).unwrap();
 	assert_eq!(b, CompactContentAddress(65538 * 64));
-	let a2 = ContentAddress::from(b);
+	let a2 = b.expand(&scheme);
 	assert_eq!(a, a2);
 }
+
+#[test]
+fn content_addresses_follow_scheme() {
+	let scheme = SizeScheme::new(vec![16, 1024 * 1024]).unwrap();
+	let a = ContentAddress { datum_size: DatumSize::Size(1), content_table: 3, entry_index: 1 };
+	assert_eq!(scheme.contents_entries(a.datum_size), 2);
+	let b = a.compact(&scheme).unwrap();
+	assert_eq!(b, CompactContentAddress(1 | (7 << 6)));
+	assert_eq!(b.expand(&scheme), a);
+	let beyond = ContentAddress { content_table: ADDRESSES_PER_CLASS / 2, ..a };
+	assert!(matches!(beyond.compact(&scheme), Err(Error::AddressSpaceFull)));
+}
@@ -1,26 +1,41 @@
-use std::path::PathBuf;
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use log::{info, trace, warn};
 use parking_lot::MappedRwLockReadGuard;
+use rand::Rng;
 
-use crate::datum_size::DatumSize;
-use crate::types::{KeyType, HashOutput};
-use crate::content::Content;
-use crate::content_address::ContentAddress;
-use crate::table::{RefCount, TableItemCount};
-use crate::index::Index;
-use crate::metadata::{Metadata, MetadataV1};
+use crate::datum_size::{DatumSize, SizeScheme};
+use crate::types::{KeyType, HashOutput, key_length};
+use crate::content::{Content, remove_content_files, move_content_files};
+use crate::content_address::CompactContentAddress;
+use crate::table::{RawItem, RefCount, TableItemCount, MAX_REF_COUNT};
+use crate::index::{Index, check_geometry};
+use crate::export::{ExportReader, ExportWriter};
+use crate::iter::{Cursor, Iter, Keys};
+use crate::inspector::Inspector;
+use crate::stats::{IndexStats, Stats};
+use crate::metadata::{Metadata, MetadataV2, MergeCheckpoint, Repack, RepackStage, Tunables, SUPPORTED_FEATURES};
 use crate::Error;
 
 /// The options builder.
 pub struct Options {
 	pub(crate) path: PathBuf,
+	pub(crate) index_path: Option<PathBuf>,
+	pub(crate) content_path: Option<PathBuf>,
+	pub(crate) oversize_path: Option<PathBuf>,
+	pub(crate) size_scheme: Option<SizeScheme>,
+	pub(crate) hasher: Option<String>,
 	pub(crate) key_bytes: usize,
 	pub(crate) index_bits: usize,
-	pub(crate) skipped_count_trigger: u8,
-	pub(crate) key_correction_trigger: usize,
-	pub(crate) oversize_trigger_mapped: usize,
-	pub(crate) oversize_shrink_mapped: usize,
-	pub(crate) min_items_backed: TableItemCount,
+	pub(crate) skipped_count_trigger: Option<u8>,
+	pub(crate) key_correction_trigger: Option<usize>,
+	pub(crate) oversize_trigger_mapped: Option<usize>,
+	pub(crate) oversize_shrink_mapped: Option<usize>,
+	pub(crate) min_items_backed: Option<TableItemCount>,
 }
 
 impl Options {
@@ -29,12 +44,17 @@ impl Options {
 		Self {
 			key_bytes: 4,
 			index_bits: 16,
-			skipped_count_trigger: 240,
-			key_correction_trigger: 32,
-			oversize_trigger_mapped: 256 * 1024 * 1024,
-			oversize_shrink_mapped: 64 * 1024 * 1024,
-			min_items_backed: 8,
+			skipped_count_trigger: None,
+			key_correction_trigger: None,
+			oversize_trigger_mapped: None,
+			oversize_shrink_mapped: None,
+			min_items_backed: None,
 			path: Default::default(),
+			index_path: None,
+			content_path: None,
+			oversize_path: None,
+			size_scheme: None,
+			hasher: None,
 		}
 	}
 
@@ -63,6 +83,53 @@ impl Options {
 		self
 	}
 
+	/// Set the path in which the index should be stored. By default it lives in the main database
+	/// path. Once the database is created, this is recorded in its metadata and needn't be given
+	/// again; giving it again with a different path is how you declare that the index was moved.
+	/// A relative path is taken to be relative to the main database path, so that the whole
+	/// database may be moved together.
+	pub fn index_path(mut self, path: PathBuf) -> Self {
+		self.index_path = Some(path);
+		self
+	}
+
+	/// Set the path in which the sized content tables should be stored. By default they live in
+	/// the main database path. It is recorded in the metadata just as with `index_path`.
+	pub fn content_path(mut self, path: PathBuf) -> Self {
+		self.content_path = Some(path);
+		self
+	}
+
+	/// Set the path in which the oversize content table and all oversize items should be stored.
+	/// By default they live in the main database path. It is recorded in the metadata just as with
+	/// `index_path`.
+	pub fn oversize_path(mut self, path: PathBuf) -> Self {
+		self.oversize_path = Some(path);
+		self
+	}
+
+	/// Set the size classes into which items are stored (default: `SizeScheme::default()`). Each
+	/// item takes the space of the smallest class it fits in, so classes which closely fit the
+	/// sizes of the values stored waste less space on padding. The scheme also sets how big the
+	/// content tables are.
+	///
+	/// `SizeScheme::small_values()` suits databases holding many values of under 32 bytes.
+	///
+	/// This only takes effect when the database is created; it is recorded in the metadata and
+	/// used from then on. Use `Database::repack` to change the scheme of an existing database.
+	pub fn size_scheme(mut self, size_scheme: SizeScheme) -> Self {
+		self.size_scheme = Some(size_scheme);
+		self
+	}
+
+	/// Set an identifier for the hasher from which the keys come (e.g. `"blake2b-256"`). It is
+	/// recorded when the database is created, and opening the database with a different one
+	/// fails. Databases with no hasher recorded take the one given when they are next opened.
+	pub fn hasher(mut self, hasher: &str) -> Self {
+		self.hasher = Some(hasher.into());
+		self
+	}
+
 	/// Set the oversize tables' mapping management properties. Whereas sized tables keep everything
 	/// mapped all the time, oversize tables (owing to the fact they are essentially unbounded in
 	/// how much they might be mapping) regularly prune the items that are mapped. This is done as a
@@ -71,35 +138,95 @@ impl Options {
 	/// The system has two parameters: a `trigger` size, which is how many bytes much be mapped in
 	/// total before a "shrinking" (unmapping) happens; and a `shrink` size which is how many bytes,
 	/// at most, may continue to be mapped at the "shrinking" is completed.
+	///
+	/// Like the other tunables, this is recorded in the metadata and used from then on; giving it
+	/// again changes what's recorded (defaults: 256MB and 64MB).
 	pub fn oversize_shrink(mut self, trigger: usize, shrink: usize) -> Self {
-		self.oversize_trigger_mapped = trigger;
-		self.oversize_shrink_mapped = shrink;
+		self.oversize_trigger_mapped = Some(trigger);
+		self.oversize_shrink_mapped = Some(shrink);
 		self
 	}
 
 	/// Set the minimum number of items that will be backed on disk. This basically sets the
-	/// minimum disk space that will be used by a table with a single element in it.
+	/// minimum disk space that will be used by a table with a single element in it (default: 8).
 	pub fn min_items_backed(mut self, min_items_backed: TableItemCount) -> Self {
-		self.min_items_backed = min_items_backed;
+		self.min_items_backed = Some(min_items_backed);
 		self
 	}
 
 	/// Ensure that the disk files never need to extend by always requiring any tables to use their
 	/// full amount.
 	pub fn all_items_backed(mut self) -> Self {
-		self.min_items_backed = 65536;
+		self.min_items_backed = Some(TableItemCount::MAX);
 		self
 	}
 
+	/// Set when the index is grown: once an insertion has to skip over an index entry more than
+	/// `skipped_count` times (default: 240), or lands at least `key_correction` entries away from
+	/// where it would ideally be (default: 32).
+	pub fn reindex_triggers(mut self, skipped_count: u8, key_correction: usize) -> Self {
+		self.skipped_count_trigger = Some(skipped_count);
+		self.key_correction_trigger = Some(key_correction);
+		self
+	}
+
+	/// The tunables of `base`, with any given explicitly overridden.
+	pub(crate) fn tunables(&self, base: Tunables) -> Tunables {
+		Tunables {
+			skipped_count_trigger: self.skipped_count_trigger.unwrap_or(base.skipped_count_trigger),
+			key_correction_trigger: self.key_correction_trigger.map_or(base.key_correction_trigger, |x| x as u32),
+			oversize_trigger_mapped: self.oversize_trigger_mapped.map_or(base.oversize_trigger_mapped, |x| x as u64),
+			oversize_shrink_mapped: self.oversize_shrink_mapped.map_or(base.oversize_shrink_mapped, |x| x as u64),
+			min_items_backed: self.min_items_backed.unwrap_or(base.min_items_backed),
+		}
+	}
+
 	/// Open the database or create one with the configured options if it doesn't yet exist.
 	pub fn open<K: KeyType>(self) -> Result<Database<K>, Error> {
 		Database::open(self)
 	}
 }
 
+/// The name of the directory, within the content and oversize paths, into which a repack puts the
+/// new tables.
+const REPACK_DIR: &str = "repack";
+
+/// How many items a merge moves between checkpoints.
+const MERGE_BATCH: u64 = 4096;
+
+/// How many bytes of values a merge holds in memory before moving them, even if it has fewer than
+/// `MERGE_BATCH` items.
+const MERGE_BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+/// How far a merge has got.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MergeProgress {
+	/// The number of items of the source which have been merged, including any merged before the
+	/// merge was resumed.
+	pub merged: u64,
+	/// The number of items in the source.
+	pub total: u64,
+}
+
+/// A way in which two databases differ, as found by `Database::diff`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Difference<K> {
+	/// The item is in this database but not the other.
+	OnlyInSelf(K),
+	/// The item is in the other database but not this one.
+	OnlyInOther(K),
+	/// The item has a different value in each database. As keys are derived from values, one of
+	/// the databases must be corrupt.
+	ValueDiffers(K),
+	/// The item has a different number of references in each database: this one's, then the
+	/// other's.
+	RefCountDiffers(K, RefCount, RefCount),
+}
+
 pub struct Database<K: KeyType> {
 	options: Options,
-	index: Index<K, ContentAddress>,
+	metadata: MetadataV2,
+	index: Index<K, CompactContentAddress>,
 	content: Content<K>,
 	_dummy: std::marker::PhantomData<K>,
 }
@@ -119,17 +246,91 @@ impl<K: KeyType> Database<K> {
 		}
 
 		// Sort out metadata.
-		let metadata = if let Some(metadata) = MetadataV1::try_read(&options.path)? {
+		let key_length = key_length::<K>();
+		let mut metadata = if let Some(mut metadata) = MetadataV2::try_read(&options.path)? {
 			info!("Opening existing SubDB [{} bytes/{}-bit]", metadata.key_bytes, metadata.index_bits);
+			// Check this before anything is written, since a write would drop the features.
+			if metadata.features & !SUPPORTED_FEATURES != 0 {
+				return Err(Error::UnsupportedVersion);
+			}
+			// Any locations given explicitly override those recorded, since parts of the database
+			// may have been moved.
+			let mut relocated = false;
+			let mut relocate = |given: &Option<PathBuf>, recorded: &mut Option<PathBuf>| {
+				if given.is_some() && given != recorded {
+					*recorded = given.clone();
+					relocated = true;
+				}
+			};
+			relocate(&options.index_path, &mut metadata.index_path);
+			relocate(&options.content_path, &mut metadata.content_path);
+			relocate(&options.oversize_path, &mut metadata.oversize_path);
+			if relocated {
+				info!("Recording relocated SubDB storage");
+				metadata.write(&options.path)?;
+			}
+			// Likewise any tunables given explicitly override those recorded.
+			let tunables = options.tunables(metadata.tunables.clone());
+			if tunables != metadata.tunables {
+				info!("Recording changed SubDB tunables");
+				metadata.tunables = tunables;
+				metadata.write(&options.path)?;
+			}
+			if matches!(&options.size_scheme, Some(s) if s != &metadata.size_scheme()) {
+				warn!("Ignoring size scheme given for existing SubDB; using the recorded one");
+			}
+
+			// Make sure that we're reading the keys that the database was written with.
+			match metadata.key_length {
+				Some(recorded) if recorded as usize != key_length => return Err(Error::IncompatibleKey(
+					format!("keys are {} bytes but the database has keys of {} bytes", key_length, recorded)
+				)),
+				Some(_) => {}
+				None => {
+					info!("Recording key length of SubDB as {} bytes", key_length);
+					metadata.key_length = Some(key_length as u32);
+					metadata.write(&options.path)?;
+				}
+			}
+			match (&options.hasher, &metadata.hasher) {
+				(Some(given), Some(recorded)) if given != recorded => return Err(Error::IncompatibleKey(
+					format!("keys are from hasher {} but the database has keys from hasher {}", given, recorded)
+				)),
+				(Some(given), None) => {
+					info!("Recording hasher of SubDB as {}", given);
+					metadata.hasher = Some(given.clone());
+					metadata.write(&options.path)?;
+				}
+				_ => {}
+			}
+			check_geometry(metadata.key_bytes, metadata.index_bits, key_length)?;
 			metadata
 		} else {
-			let metadata = MetadataV1::from(&options);
+			check_geometry(options.key_bytes, options.index_bits, key_length)?;
+			let mut metadata = MetadataV2::from(&options);
+			metadata.key_length = Some(key_length as u32);
 			metadata.write(&options.path)?;
 			info!("Creating new SubDB [{} bytes/{}-bit]", metadata.key_bytes, metadata.index_bits);
 			metadata
 		};
 
-		let mut index_filename = options.path.clone();
+		// A repack which was interrupted after all items were moved only has files left to shuffle.
+		if matches!(&metadata.repack, Some(r) if r.stage != RepackStage::Moving) {
+			info!("Finishing interrupted repack of SubDB");
+			Self::replace_repacked(&mut metadata, &options.path)?;
+		}
+
+		let index_path = metadata.index_path(&options.path);
+		let content_path = metadata.content_path(&options.path);
+		let oversize_path = metadata.oversize_path(&options.path);
+		for path in &[&index_path, &content_path, &oversize_path] {
+			assert!(!path.is_file(), "Path must be a directory or not exist.");
+			if !path.is_dir() {
+				std::fs::create_dir_all(path)?;
+			}
+		}
+
+		let mut index_filename = index_path;
 		index_filename.push("index.subdb");
 		let index = Index::open(
 			index_filename,
@@ -138,24 +339,34 @@ impl<K: KeyType> Database<K> {
 		)?;
 
 		let content = Content::open(
-			options.path.clone(),
-			options.oversize_trigger_mapped,
-			options.oversize_shrink_mapped,
-				options.min_items_backed,
+			content_path,
+			oversize_path,
+			metadata.size_scheme(),
+			metadata.tunables.oversize_trigger_mapped as usize,
+			metadata.tunables.oversize_shrink_mapped as usize,
+			metadata.tunables.min_items_backed,
 		)?;
 
-		Ok(Self {
-			options, index, content, _dummy: Default::default()
-		})
+		let mut db = Self {
+			options, metadata, index, content, _dummy: Default::default()
+		};
+		if db.metadata.repack.is_some() {
+			info!("Resuming interrupted repack of SubDB");
+			db.finish_repack()?;
+		}
+		Ok(db)
 	}
 
 	pub fn reindex(&mut self, key_bytes: usize, index_bits: usize) -> Result<(), Error> {
-		let mut temp_filename = self.options.path.clone();
+		let index_path = self.metadata.index_path(&self.options.path);
+		let mut temp_filename = index_path.clone();
 		temp_filename.push("new-index.subdb");
 
-		let mut index_filename = self.options.path.clone();
+		let mut index_filename = index_path;
 		index_filename.push("index.subdb");
 
+		check_geometry(key_bytes, index_bits, key_length::<K>())?;
+
 		// First we create the new index.
 		// We don't want to keep it around as we'll be renaming it and need it to be closed.
 		Index::from_existing(temp_filename.clone(), &mut self.index, key_bytes, index_bits)?;
@@ -167,7 +378,9 @@ impl<K: KeyType> Database<K> {
 		std::fs::remove_file(index_filename.clone())?;
 		std::fs::rename(temp_filename, index_filename.clone())?;
 		// ...and reset the metadata.
-		MetadataV1 { key_bytes, index_bits }.write(&self.options.path)?;
+		self.metadata.key_bytes = key_bytes;
+		self.metadata.index_bits = index_bits;
+		self.metadata.write(&self.options.path)?;
 		info!("Creating new SubDB [{} bytes/{}-bit]", key_bytes, index_bits);
 
 
@@ -177,6 +390,502 @@ impl<K: KeyType> Database<K> {
 		Ok(())
 	}
 
+	/// Reclaim the space left behind by removed items: live items are moved out of sparsely used
+	/// content tables and into the free slots of denser ones, table files are truncated and empty
+	/// tables deleted. Returns the number of bytes reclaimed.
+	pub fn compact(&mut self) -> usize {
+		let before = self.content.bytes_used();
+		let index = &mut self.index;
+		self.content.compact(|key, old, new| {
+			let moved = index.edit_out(key, |address|
+				if &address == old { Ok((Some(Some(*new)), ())) } else { Err(()) }
+			);
+			if moved.is_err() {
+				warn!(target: "database", "Compacted item {:?} not in index. Database corruption?", key);
+			}
+		});
+		self.commit();
+		let reclaimed = before.saturating_sub(self.content.bytes_used());
+		info!(target: "database", "Compaction reclaimed {} bytes", reclaimed);
+		reclaimed
+	}
+
+	/// Move every item into new content tables with the size classes of `scheme`, keeping their
+	/// reference counts. The new tables are built alongside the old ones, which are emptied as we
+	/// go, so little more space is needed than the database already takes.
+	///
+	/// If interrupted, the repack is resumed when the database is next opened.
+	pub fn repack(&mut self, scheme: SizeScheme) -> Result<(), Error> {
+		self.finish_repack()?;
+		if &scheme != self.content.scheme() {
+			// Make sure that every item will have an address before we start.
+			let mut counts = [0usize; 64];
+			for (size, count) in self.content.value_sizes() {
+				counts[u8::from(scheme.nearest(size as usize)) as usize] += count as usize;
+			}
+			if counts.iter().enumerate().filter(|(_, &count)| count > 0).any(|(s, &count)| {
+				let datum_size = DatumSize::from(s as u8);
+				count > scheme.max_tables(datum_size) * scheme.contents_entries(datum_size)
+			}) {
+				return Err(Error::AddressSpaceFull)
+			}
+			info!(target: "database", "Repacking into {} size classes", scheme.classes());
+			self.metadata.repack = Some(Repack { scheme, stage: RepackStage::Moving });
+			self.metadata.write(&self.options.path)?;
+			self.finish_repack()?;
+		}
+		Ok(())
+	}
+
+	/// Finish the repack recorded in our metadata, if any.
+	fn finish_repack(&mut self) -> Result<(), Error> {
+		let scheme = match &self.metadata.repack {
+			Some(repack) => repack.scheme.clone(),
+			None => return Ok(()),
+		};
+		let content_path = self.metadata.content_path(&self.options.path);
+		let oversize_path = self.metadata.oversize_path(&self.options.path);
+		let new_content_path = content_path.join(REPACK_DIR);
+		let new_oversize_path = oversize_path.join(REPACK_DIR);
+		std::fs::create_dir_all(&new_content_path)?;
+		std::fs::create_dir_all(&new_oversize_path)?;
+
+		{
+			let mut target = Content::<K>::open(
+				new_content_path,
+				new_oversize_path,
+				scheme.clone(),
+				self.metadata.tunables.oversize_trigger_mapped as usize,
+				self.metadata.tunables.oversize_shrink_mapped as usize,
+				self.metadata.tunables.min_items_backed,
+			)?;
+			let index = &mut self.index;
+			let content = &mut self.content;
+
+			// If we were interrupted, the last item moved may not have been released from the old
+			// content yet; if so, its index entry still points there.
+			target.for_each(|key, new| {
+				let _ = index.edit_out(key, |address| if content.holds(&address, key) {
+					content.release(&address)?;
+					Ok((Some(Some(*new)), ()))
+				} else {
+					Err(())
+				});
+			});
+
+			content.drain(|key, ref_count, data, old| {
+				let moved = index.edit_out(key, |address| if &address == old {
+					let new = target.restore(key, data, ref_count)
+						.expect("Checked that every item has an address before repacking; qed");
+					Ok((Some(Some(new)), ()))
+				} else {
+					Err(())
+				});
+				// If the index already points into the new content then this is an item which we
+				// were interrupted from releasing after it was moved.
+				if moved.is_err() && index.with_item_try(key, |entry|
+					if target.holds(&entry.address, key) { Ok(()) } else { Err(()) }
+				).is_none() {
+					warn!(target: "database", "Repacked item {:?} not in index. Database corruption?", key);
+				}
+			});
+			target.commit();
+			index.commit();
+		}
+
+		self.metadata.repack = Some(Repack { scheme: scheme.clone(), stage: RepackStage::Deleting });
+		self.metadata.write(&self.options.path)?;
+		Self::replace_repacked(&mut self.metadata, &self.options.path)?;
+		self.content = Content::open(
+			content_path,
+			oversize_path,
+			scheme,
+			self.metadata.tunables.oversize_trigger_mapped as usize,
+			self.metadata.tunables.oversize_shrink_mapped as usize,
+			self.metadata.tunables.min_items_backed,
+		)?;
+		Ok(())
+	}
+
+	/// Replace the old content tables with those built by a repack once every item has been
+	/// moved, and record the new scheme in `metadata`.
+	fn replace_repacked(metadata: &mut MetadataV2, path: &Path) -> Result<(), Error> {
+		let path = path.to_path_buf();
+		let mut repack = metadata.repack.clone().expect("Only called during a repack; qed");
+		let content_path = metadata.content_path(&path);
+		let oversize_path = metadata.oversize_path(&path);
+		if repack.stage == RepackStage::Deleting {
+			remove_content_files(&content_path)?;
+			remove_content_files(&oversize_path)?;
+			repack.stage = RepackStage::Renaming;
+			metadata.repack = Some(repack.clone());
+			metadata.write(&path)?;
+		}
+		move_content_files(&content_path.join(REPACK_DIR), &content_path)?;
+		move_content_files(&oversize_path.join(REPACK_DIR), &oversize_path)?;
+		let _ = std::fs::remove_dir(content_path.join(REPACK_DIR));
+		let _ = std::fs::remove_dir(oversize_path.join(REPACK_DIR));
+		metadata.size_scheme = repack.scheme;
+		metadata.repack = None;
+		metadata.write(&path)?;
+		info!(target: "database", "Repack finished");
+		Ok(())
+	}
+
+	/// The size classes into which items are stored.
+	pub fn size_scheme(&self) -> &SizeScheme {
+		self.content.scheme()
+	}
+
+	/// How many items there are of each value size, in order of size. This is the form of
+	/// histogram which `SizeScheme::optimal` takes.
+	pub fn value_sizes(&self) -> Vec<(u32, u32)> {
+		self.content.value_sizes()
+	}
+
+	/// How the items use their space, by size class. Every item is visited, but no value is read.
+	pub fn stats(&self) -> Stats {
+		Stats {
+			classes: self.content.class_stats(),
+			index_entry_bytes: self.index.entry_size(),
+		}
+	}
+
+	/// The health of the index: how full it is, how far entries lie from their ideal positions and
+	/// how close the next insertion is to causing a reindex. Every position is read.
+	pub fn index_stats(&self) -> IndexStats {
+		let tunables = &self.metadata.tunables;
+		IndexStats {
+			triggers: (tunables.skipped_count_trigger, tunables.key_correction_trigger as usize),
+			..self.index.stats()
+		}
+	}
+
+	/// Check the database for consistency, returning a description of each problem found. An
+	/// empty result means all is well.
+	pub fn verify(&self) -> Vec<String> {
+		self.content.check()
+	}
+
+	/// An iterator over the key, reference count and value of every item, in the order in which
+	/// they're stored in the content tables. Reading them in this order is largely sequential, and
+	/// doesn't involve the index at all, so reindexing doesn't disturb it.
+	pub fn iter(&self) -> Iter<'_, K> {
+		Iter::new(&self.content, DatumSize::from(0), DatumSize::Oversize)
+	}
+
+	/// An iterator over the key of every item, in the order of `iter`.
+	pub fn keys(&self) -> Keys<'_, K> {
+		Keys::new(&self.content, DatumSize::from(0), DatumSize::Oversize)
+	}
+
+	/// Like `iter`, but resuming from where the iteration which gave `cursor` got to. Resuming
+	/// the iteration of a single size class stays within that size class.
+	pub fn iter_from(&self, cursor: &Cursor) -> Result<Iter<'_, K>, Error> {
+		Iter::resume(&self.content, cursor)
+	}
+
+	/// Like `keys`, but resuming from where the iteration which gave `cursor` got to.
+	pub fn keys_from(&self, cursor: &Cursor) -> Result<Keys<'_, K>, Error> {
+		Keys::resume(&self.content, cursor)
+	}
+
+	/// Like `iter`, but only over the items of size class `datum_size`.
+	pub fn iter_size_class(&self, datum_size: DatumSize) -> Iter<'_, K> {
+		Iter::new(&self.content, datum_size, datum_size)
+	}
+
+	/// Like `keys`, but only over the items of size class `datum_size`.
+	pub fn keys_size_class(&self, datum_size: DatumSize) -> Keys<'_, K> {
+		Keys::new(&self.content, datum_size, datum_size)
+	}
+
+	/// Call `f` with the key, reference count and value of every item, spreading the work over
+	/// `threads` threads. Each content table is scanned by a single thread, and threads take the
+	/// next table not yet taken as they finish each one, so the items of a table are seen in order
+	/// but there's no order between tables.
+	pub fn par_for_each(&self, threads: usize, f: impl Fn(&K, RefCount, &[u8]) + Sync) {
+		let tables = self.content.table_ids();
+		let next = AtomicUsize::new(0);
+		let content = &self.content;
+		crossbeam_utils::thread::scope(|scope| {
+			for _ in 0..threads.max(1).min(tables.len()) {
+				scope.spawn(|_| {
+					while let Some(&(datum_size, content_table)) = tables.get(next.fetch_add(1, Ordering::Relaxed)) {
+						content.for_each_in_table(datum_size, content_table, &f);
+					}
+				});
+			}
+		}).unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+	}
+
+	/// Pick `n` different items uniformly at random, or every item if there are no more than `n`,
+	/// and return the key and value of each.
+	///
+	/// Items are picked by trying random positions in the index until enough of them turn out to
+	/// hold an entry, so this takes time in proportion to `n` rather than the size of the
+	/// database, unless `n` is most of the items.
+	pub fn sample(&self, n: usize, rng: &mut impl Rng) -> Vec<(K, Vec<u8>)> {
+		let items = self.content.info().iter().map(|(_, (_, used, _, _), _)| *used as usize).sum::<usize>();
+		if n >= items {
+			return self.iter().map(|(key, _, value)| (key, value)).collect()
+		}
+		let mut picked = HashSet::new();
+		let mut result = Vec::with_capacity(n);
+		while result.len() < n {
+			let position = rng.gen_range(0, self.index.len());
+			if let Some(address) = self.index.value_at(position) {
+				if picked.insert(position) {
+					let key = self.content.item_hash(&address).expect("Item is indexed. Database corrupt?");
+					let value = self.content.item_ref(&address, None).expect("Item is indexed. Database corrupt?");
+					result.push((key, value.to_vec()));
+				}
+			}
+		}
+		result
+	}
+
+	/// Compare this database with the one which `other` inspects, calling `f` with each difference
+	/// and returning how many there were. Neither database is changed, and they needn't share an
+	/// index geometry or size scheme, but their keys must be the same length.
+	///
+	/// Both indexes are walked once, side by side, in an order of keys which doesn't depend on
+	/// their geometry, so this takes time linear in the number of items, each value is read just
+	/// once and little memory is needed.
+	pub fn diff(&self, other: &Inspector, mut f: impl FnMut(Difference<K>)) -> Result<usize, Error> {
+		if other.key_length() != key_length::<K>() {
+			return Err(Error::IncompatibleKey(format!("keys are {} bytes but the other database has keys of {} bytes",
+				key_length::<K>(), other.key_length())))
+		}
+		let mut count = 0;
+		let mut report = |difference| {
+			count += 1;
+			f(difference)
+		};
+		let content = &self.content;
+		let mut ours = self.index.in_key_order(|address| content.item_hash(address)
+			.expect("Item is indexed. Database corrupt?")
+		).peekable();
+		let mut theirs = other.in_key_order().peekable();
+		loop {
+			let order = match (ours.peek(), theirs.peek()) {
+				(None, None) => break,
+				(Some(_), None) => cmp::Ordering::Less,
+				(None, Some(_)) => cmp::Ordering::Greater,
+				(Some((a, key, _)), Some((b, other_key, _))) => (a, key.as_ref()).cmp(&(b, other_key.as_ref())),
+			};
+			match order {
+				cmp::Ordering::Less => {
+					let (_, key, _) = ours.next().expect("peeked; qed");
+					report(Difference::OnlyInSelf(key));
+				}
+				cmp::Ordering::Greater => {
+					let (_, other_key, _) = theirs.next().expect("peeked; qed");
+					let mut key = K::default();
+					key.as_mut().copy_from_slice(&other_key);
+					report(Difference::OnlyInOther(key));
+				}
+				cmp::Ordering::Equal => {
+					let (_, key, address) = ours.next().expect("peeked; qed");
+					let (_, _, other_address) = theirs.next().expect("peeked; qed");
+					let other_item = other.item(&other_address).expect("Item is indexed. Database corrupt?");
+					let same_value = *content.item_ref(&address, None).expect("Item is indexed. Database corrupt?")
+						== other_item.value[..];
+					content.release_mapping(&address);
+					if !same_value {
+						report(Difference::ValueDiffers(key));
+						continue
+					}
+					let ref_count = content.item_ref_count(&address, None).expect("Item is indexed. Database corrupt?");
+					if ref_count != other_item.ref_count {
+						report(Difference::RefCountDiffers(key, ref_count, other_item.ref_count))
+					}
+				}
+			}
+		}
+		Ok(count)
+	}
+
+	/// Write every item, along with its key and reference count, to `output` as an export stream,
+	/// returning the number of items written. The stream may be imported into a database of any
+	/// size scheme and index size, so long as its keys are the same length.
+	pub fn export(&self, output: impl Write) -> Result<u64, Error> {
+		let mut writer = ExportWriter::new(output, key_length::<K>())?;
+		let mut result = Ok(());
+		let content = &self.content;
+		content.for_each(|key, address| if result.is_ok() {
+			let ref_count = content.item_ref_count(address, None).expect("Item is allocated. Database corrupt?");
+			let value = content.item_ref(address, None).expect("Item is allocated. Database corrupt?");
+			result = writer.write(key.as_ref(), ref_count, &value);
+			drop(value);
+			content.release_mapping(address);
+		});
+		result?;
+		writer.finish()
+	}
+
+	/// Insert every item of the export stream `input`, as written by `export`, returning the
+	/// number of items read. Items already in the database gain the references of those imported.
+	///
+	/// The whole stream is read and its checksum checked before anything is inserted, so a corrupt
+	/// stream leaves the database as it was. So that `input` needn't be read twice, it's first
+	/// copied into a temporary file in the database's path, which is removed afterwards.
+	pub fn import<R: Read>(&mut self, mut input: R) -> Result<u64, Error> {
+		let staged = self.options.path.join("import.subdb.tmp");
+		let result = File::create(&staged)
+			.and_then(|file| {
+				let mut file = BufWriter::new(file);
+				std::io::copy(&mut input, &mut file)?;
+				file.flush()
+			})
+			.map_err(Error::from)
+			.and_then(|()| self.import_staged(&staged));
+		let _ = std::fs::remove_file(&staged);
+		result
+	}
+
+	/// Check the export stream in the file `staged` and then insert every item of it.
+	fn import_staged(&mut self, staged: &Path) -> Result<u64, Error> {
+		let open = || -> Result<_, Error> {
+			ExportReader::new(BufReader::new(File::open(staged)?))
+		};
+		let mut reader = open()?;
+		if reader.key_length() != key_length::<K>() {
+			return Err(Error::IncompatibleKey(format!("keys are {} bytes but the export has keys of {} bytes",
+				key_length::<K>(), reader.key_length())))
+		}
+		while reader.next_record()?.is_some() {}
+
+		let mut reader = open()?;
+		let mut count = 0;
+		while let Some(record) = reader.next_record()? {
+			let mut key = K::default();
+			key.as_mut().copy_from_slice(&record.key);
+			self.add_references(&record.value, &key, record.ref_count)?;
+			count += 1;
+		}
+		self.commit();
+		Ok(count)
+	}
+
+	/// Insert every item of the database in `source_path`, adding its reference count to that of
+	/// any item with the same key, and return the number of items merged in all. `progress` is
+	/// called after every few thousand items and once at the end. The source is only read, through
+	/// an `Inspector`, so it may be in use elsewhere.
+	///
+	/// Items are merged in batches, in the order in which the source stores them, and before each
+	/// batch the reference counts which it is about to change are recorded. If the merge is
+	/// interrupted or fails then merging from the same source again puts those reference counts
+	/// back and carries on from the start of the batch, so nothing is merged twice; the source
+	/// mustn't change in between.
+	pub fn merge_from(&mut self, source_path: &Path, mut progress: impl FnMut(MergeProgress)) -> Result<u64, Error> {
+		let source_path = source_path.canonicalize()?;
+		if source_path == self.options.path.canonicalize()? {
+			return Err(Error::BadOptions("cannot merge a database into itself".into()))
+		}
+		let source = Inspector::open(&source_path)?;
+		if source.key_length() != key_length::<K>() {
+			return Err(Error::IncompatibleKey(format!("keys are {} bytes but the source has keys of {} bytes",
+				key_length::<K>(), source.key_length())))
+		}
+		let source_name = source_path.to_string_lossy().into_owned();
+		let resume = match MergeCheckpoint::read(&self.options.path)? {
+			Some(checkpoint) if checkpoint.source == source_name => {
+				info!(target: "database", "Resuming merge from {} after {} items", source_name, checkpoint.merged);
+				for (key, ref_count) in &checkpoint.prior_ref_counts {
+					let mut hash = K::default();
+					if hash.as_ref().len() != key.len() {
+						return Err(Error::BadMetadata)
+					}
+					hash.as_mut().copy_from_slice(key);
+					self.restore_references(&hash, *ref_count);
+				}
+				self.commit();
+				checkpoint.merged
+			}
+			_ => 0,
+		};
+		let total = source.tables().iter().map(|table| table.used as u64).sum();
+
+		let mut merged = resume;
+		let mut skip = resume;
+		let mut batch = vec![];
+		let mut batch_bytes = 0;
+		let mut result = Ok(());
+		source.for_each_item(|_, item| {
+			if result.is_err() {
+				return
+			}
+			if skip > 0 {
+				skip -= 1;
+				return
+			}
+			batch_bytes += item.value.len();
+			batch.push(item.clone());
+			if batch.len() == MERGE_BATCH as usize || batch_bytes >= MERGE_BATCH_BYTES {
+				result = self.merge_batch(&source_name, merged, &batch);
+				merged += batch.len() as u64;
+				batch.clear();
+				batch_bytes = 0;
+				if result.is_ok() {
+					progress(MergeProgress { merged, total });
+				}
+			}
+		});
+		result?;
+		if !batch.is_empty() {
+			self.merge_batch(&source_name, merged, &batch)?;
+			merged += batch.len() as u64;
+		}
+		MergeCheckpoint::clear(&self.options.path)?;
+		progress(MergeProgress { merged, total });
+		Ok(merged)
+	}
+
+	/// Merge `batch`, the items of the source called `source_name` which follow the first `merged`,
+	/// having first recorded the reference counts which it changes.
+	fn merge_batch(&mut self, source_name: &str, merged: u64, batch: &[RawItem]) -> Result<(), Error> {
+		let keys = batch.iter()
+			.map(|item| {
+				let mut key = K::default();
+				key.as_mut().copy_from_slice(&item.key);
+				key
+			})
+			.collect::<Vec<_>>();
+		let prior_ref_counts = keys.iter()
+			.map(|key| (key.as_ref().to_vec(), self.get_ref_count(key)))
+			.collect();
+		MergeCheckpoint { source: source_name.into(), merged, prior_ref_counts }.write(&self.options.path)?;
+		for (key, item) in keys.iter().zip(batch) {
+			if let Err(e) = self.add_references(&item.value, key, item.ref_count) {
+				self.commit();
+				return Err(e)
+			}
+		}
+		self.commit();
+		Ok(())
+	}
+
+	/// Give the item with `hash` the `ref_count` references it had before a merge was interrupted,
+	/// removing it altogether if it had none.
+	fn restore_references(&mut self, hash: &K, ref_count: RefCount) {
+		let content = &mut self.content;
+		let restored = if ref_count == 0 {
+			self.index.edit_out(hash, |address| {
+				if &content.item_hash(&address)? != hash {
+					return Err(())
+				}
+				content.release(&address).map(|()| (Some(None), ()))
+			}).is_ok()
+		} else {
+			self.index.with_item_try(hash, |entry| content.set_ref_count(&entry.address, Some(hash), ref_count))
+				.is_some()
+		};
+		if !restored && ref_count > 0 {
+			warn!(target: "database", "Item {:?} lost during interrupted merge. Database corruption?", hash);
+		}
+	}
+
 	pub fn commit(&mut self) {
 		self.index.commit();
 		self.content.commit();
@@ -186,7 +895,12 @@ impl<K: KeyType> Database<K> {
 		self.info().into_iter().map(|x| (x.1).3).sum()
 	}
 
-	pub fn info(&self) -> Vec<((DatumSize, usize), (TableItemCount, TableItemCount, usize, usize))> {
+	/// Information on every content table: its size class and index; how many items it has room
+	/// for, how many it holds, and how many bytes it uses and maps; and how many content addresses
+	/// its size class has left, of the number it may use in all. Once a size class has none left,
+	/// new items of that class can't be stored; repacking into a scheme with more classes around
+	/// that size spreads its items out.
+	pub fn info(&self) -> Vec<((DatumSize, usize), (TableItemCount, TableItemCount, usize, usize), (usize, usize))> {
 		self.content.info()
 	}
 
@@ -212,22 +926,40 @@ impl<K: KeyType> Database<K> {
 		).unwrap_or(0)
 	}
 
+	/// Store an item, returning how many references it now has and its key. Panics if the item is
+	/// new and its size class has no content addresses left; see `try_store`.
 	pub fn store(&mut self, data: &[u8]) -> (RefCount, K) where K: HashOutput {
+		self.try_store(data).expect("No content addresses left for the item's size class")
+	}
+
+	/// Store an item as `store` does, failing with `AddressSpaceFull` if the item is new and its
+	/// size class has no content addresses left.
+	pub fn try_store(&mut self, data: &[u8]) -> Result<(RefCount, K), Error> where K: HashOutput {
 		let hash = K::from_data(data);
-		let rc = self.insert(data, &hash);
-		(rc, hash)
+		let rc = self.try_insert(data, &hash)?;
+		Ok((rc, hash))
 	}
 
+	/// Insert an item, or add a reference to it if it's already there, returning how many
+	/// references it now has. Panics if the item is new and its size class has no content
+	/// addresses left; see `try_insert`.
 	pub fn insert(&mut self, data: &[u8], hash: &K) -> RefCount {
+		self.try_insert(data, hash).expect("No content addresses left for the item's size class")
+	}
+
+	/// Insert an item as `insert` does, failing with `AddressSpaceFull` if the item is new and its
+	/// size class has no content addresses left.
+	pub fn try_insert(&mut self, data: &[u8], hash: &K) -> Result<RefCount, Error> {
 		trace!(target: "index", "Inserting data {:?}",
 			std::str::from_utf8(data).map_or_else(|_| hex::encode(data), |s| s.to_owned())
 		);
 		let r = loop {
+			let mut failed = None;
 			match {
 				let content = &mut self.content;
 				self.index.edit_in(
 					hash,
-					|maybe_entry: Option<&ContentAddress>| -> Result<(Option<ContentAddress>, RefCount), ()> {
+					|maybe_entry: Option<&CompactContentAddress>| -> Result<(Option<CompactContentAddress>, RefCount), ()> {
 						if let Some(address) = maybe_entry {
 							// Same item (almost certainly) - just need to bump the ref count on the
 							// data.
@@ -238,12 +970,19 @@ impl<K: KeyType> Database<K> {
 									(None, r)
 								})
 						} else {
-							// Nothing there - insert the new item.
-							Ok((Some(content.emplace(hash, data)), 1))
+							// Nothing there - insert the new item, or leave the index be if we can't.
+							match content.emplace(hash, data) {
+								Ok(address) => Ok((Some(address), 1)),
+								Err(e) => {
+									failed = Some(e);
+									Ok((None, 0))
+								}
+							}
 						}
 					},
 				)
 			} {
+				Ok(_) if failed.is_some() => return Err(failed.expect("checked in guard; qed")),
 				Ok(r) => break r,
 				Err(Error::IndexFull) => {
 					let (key_bytes, index_bits) = self.index.next_size();
@@ -253,18 +992,43 @@ impl<K: KeyType> Database<K> {
 			}
 		};
 
-		let watermarks = self.index.take_watermarks();
-		if watermarks.0 > self.options.skipped_count_trigger
-			|| watermarks.1 >= self.options.key_correction_trigger
+		// Reindexing starts the watermarks afresh. Otherwise they're left be, so that `index_stats`
+		// may report them, unless the reindex fails: we don't want to try again on every insertion.
+		let watermarks = self.index.watermarks();
+		let tunables = &self.metadata.tunables;
+		if watermarks.0 > tunables.skipped_count_trigger
+			|| watermarks.1 >= tunables.key_correction_trigger as usize
 		{
 			let (key_bytes, index_bits) = self.index.next_size();
 			info!(target: "database", "Watermark triggered. Reindexing to [{} bytes/{} bits]", key_bytes, index_bits);
 			if self.reindex(key_bytes, index_bits).is_err() {
 				warn!("Error while reindexing. Things will probably go badly wrong now.");
+				self.index.reset_watermarks();
 			};
 		}
 
-		r
+		Ok(r)
+	}
+
+	/// Add `ref_count` references to an item, storing it if it's new, and return how many
+	/// references it then has. An item can have at most `MAX_REF_COUNT` references.
+	fn add_references(&mut self, data: &[u8], hash: &K, ref_count: RefCount) -> Result<RefCount, Error> {
+		if ref_count == 0 {
+			return Ok(self.get_ref_count(hash))
+		}
+		let inserted = self.try_insert(data, hash)?;
+		let total = inserted.saturating_add(ref_count - 1);
+		if total == inserted {
+			return Ok(total)
+		}
+		if total > MAX_REF_COUNT {
+			warn!(target: "database", "Item {:?} has too many references; keeping {}", hash, MAX_REF_COUNT);
+		}
+		let total = total.min(MAX_REF_COUNT);
+		let content = &mut self.content;
+		self.index.with_item_try(hash, |entry| content.set_ref_count(&entry.address, Some(hash), total))
+			.expect("Item was just inserted; qed");
+		Ok(total)
 	}
 
 	pub fn remove(&mut self, hash: &K) -> Result<RefCount, ()> {
@@ -1,8 +1,23 @@
-use std::mem::size_of;
 use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use parity_scale_codec::{self as codec, Encode, Decode};
+use crate::Error;
 
 const MAX_SIZE: u8 = 63;
 
+/// The default most bytes that the items of a content table may take.
+const DEFAULT_TABLE_BYTES: usize = 2048 * 1024;
+
+/// The default most items that a content table may have.
+const DEFAULT_TABLE_ENTRIES: usize = 65536;
+
+/// The number of items of each size class which a `CompactContentAddress` is able to address.
+pub const ADDRESSES_PER_CLASS: usize = 1 << 26;
+
+/// The most items that a content table may have under any scheme; we must be able to address at
+/// least one whole table.
+pub const MAX_TABLE_ENTRIES: usize = ADDRESSES_PER_CLASS;
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DatumSize {
 	Oversize,
@@ -13,189 +28,444 @@ impl fmt::Debug for DatumSize {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			DatumSize::Oversize => write!(f, "Oversize"),
-			DatumSize::Size(z) => write!(f, "Size(#{} = {} bytes)", z, self.size().unwrap()),
+			DatumSize::Size(z) => write!(f, "Size(#{})", z),
+		}
+	}
+}
+
+impl From<u8> for DatumSize {
+	fn from(x: u8) -> Self {
+		if x < MAX_SIZE {
+			DatumSize::Size(x)
+		} else {
+			DatumSize::Oversize
+		}
+	}
+}
+
+impl From<DatumSize> for u8 {
+	fn from(x: DatumSize) -> u8 {
+		match x {
+			DatumSize::Oversize => MAX_SIZE,
+			DatumSize::Size(x) => x,
+		}
+	}
+}
+
+/// The item size of sized class `size_class` in the default scheme: 32 bytes, going up in steps
+/// of 1/8 to 512 bytes and then in steps of 1/4 to 98,304 bytes.
+fn default_size(size_class: u8) -> usize {
+	if size_class < 32 {
+		let exp = size_class as usize / 8;
+		let tweak = size_class as usize % 8;
+		let base = 32usize << exp;
+		base + base / 8 * tweak
+	} else {
+		let exp = size_class as usize / 4 - 4;
+		let tweak = size_class as usize % 4;
+		let base = 32usize << exp;
+		base + base / 4 * tweak
+	}
+}
+
+/// The item sizes of each sized datum class, along with the geometry of the content tables which
+/// hold them. This is chosen when a database is created and recorded in its metadata; items larger
+/// than the largest class are oversize.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SizeScheme {
+	sizes: Vec<u32>,
+	/// The most bytes that the items of a sized content table may take.
+	table_bytes: usize,
+	/// The most items that a content table may have.
+	table_entries: usize,
+}
+
+impl Default for SizeScheme {
+	fn default() -> Self {
+		Self {
+			sizes: (0..MAX_SIZE).map(|z| default_size(z) as u32).collect(),
+			table_bytes: DEFAULT_TABLE_BYTES,
+			table_entries: DEFAULT_TABLE_ENTRIES,
 		}
 	}
 }
 
-impl DatumSize {
+impl SizeScheme {
+	/// The default scheme with three extra classes of 8, 16 and 24 bytes, for databases with many
+	/// small values which would otherwise all take 32 bytes. To make room, the classes above 32KB
+	/// go up in steps of 1/2 rather than 1/4.
+	///
+	/// Each item also stores its whole key, which is needed to tell it from others with the same
+	/// index entry, along with three bytes of reference count and size correction. So this saves
+	/// at most 24 bytes per item. With 32-byte keys, an item of up to 24 bytes then costs less
+	/// than twice the size of its key and value together, its index entry included; with the
+	/// default scheme, one of a byte or two costs more.
+	pub fn small_values() -> Self {
+		let sizes = [8, 16, 24].iter().cloned()
+			.chain((0..MAX_SIZE).filter(|z| !matches!(z, 57 | 59 | 61)).map(default_size))
+			.collect();
+		Self::new(sizes).expect("Sizes are increasing and exactly MAX_SIZE; qed")
+	}
+
+	/// Create a new scheme whose classes have item sizes `sizes`. These must be strictly
+	/// increasing and there may be at most 63 of them.
+	pub fn new(sizes: Vec<usize>) -> Result<Self, Error> {
+		if sizes.is_empty()
+			|| sizes.len() > MAX_SIZE as usize
+			|| sizes[0] == 0
+			|| sizes.windows(2).any(|w| w[0] >= w[1])
+			|| *sizes.last().expect("sizes is not empty; qed") > u32::MAX as usize
+		{
+			return Err(Error::BadSizeScheme)
+		}
+		Ok(Self {
+			sizes: sizes.into_iter().map(|s| s as u32).collect(),
+			table_bytes: DEFAULT_TABLE_BYTES,
+			table_entries: DEFAULT_TABLE_ENTRIES,
+		})
+	}
+
+	/// Use content tables whose items take at most `table_bytes` bytes and which have at most
+	/// `table_entries` items (default: 2MB and 65536). Larger tables mean fewer files, but more
+	/// space set aside for each size class in use. Every table can hold at least one item, and
+	/// there may be no more than 2^26 items per table.
+	pub fn with_geometry(mut self, table_bytes: usize, table_entries: usize) -> Result<Self, Error> {
+		if table_bytes == 0 || table_entries == 0 || table_entries > MAX_TABLE_ENTRIES {
+			return Err(Error::BadSizeScheme)
+		}
+		self.table_bytes = table_bytes;
+		self.table_entries = table_entries;
+		Ok(self)
+	}
+
+	/// The most bytes that the items of a sized content table may take.
+	pub fn table_bytes(&self) -> usize {
+		self.table_bytes
+	}
+
+	/// The most items that a content table may have.
+	pub fn table_entries(&self) -> usize {
+		self.table_entries
+	}
+
+	/// The item sizes of each class.
+	pub fn sizes(&self) -> impl Iterator<Item = usize> + '_ {
+		self.sizes.iter().map(|&s| s as usize)
+	}
+
+	/// The number of sized classes.
+	pub fn classes(&self) -> usize {
+		self.sizes.len()
+	}
+
 	/// The size of a datum, or `None` if the datum is oversized.
-	pub fn size(&self) -> Option<usize> {
-		match *self {
+	pub fn size(&self, datum_size: DatumSize) -> Option<usize> {
+		match datum_size {
 			DatumSize::Oversize => None,
-			DatumSize::Size(size_class) => {
-				assert!(size_class < MAX_SIZE);
-				if size_class < 32 {
-					let exp = size_class as usize / 8;
-					let tweak = size_class as usize % 8;
-					let base = 32usize << exp;
-					Some(base + base / 8 * tweak)
-				} else {
-					let exp = size_class as usize / 4 - 4;
-					let tweak = size_class as usize % 4;
-					let base = 32usize << exp;
-					Some(base + base / 4 * tweak)
-				}
-			}
+			DatumSize::Size(size_class) => Some(*self.sizes.get(size_class as usize)
+				.expect("Size class not in scheme. Database corrupt?") as usize
+			),
 		}
 	}
 
+	/// The size of the largest class. Anything larger is oversize.
+	pub fn max_size(&self) -> usize {
+		*self.sizes.last().expect("Schemes have at least one class; qed") as usize
+	}
+
 	/// The nearest datum size for `s`.
-	pub fn nearest(s: usize) -> Self {
-		if s <= 32 {
-			return DatumSize::Size(0)
+	pub fn nearest(&self, s: usize) -> DatumSize {
+		match self.sizes.binary_search(&(s.min(u32::MAX as usize) as u32)) {
+			Ok(i) => DatumSize::Size(i as u8),
+			Err(i) if i < self.sizes.len() => DatumSize::Size(i as u8),
+			Err(_) => DatumSize::Oversize,
 		}
-		let exp = size_of::<usize>() as usize * 8 - s.leading_zeros() as usize - 6;
-		let base = 32usize << exp;
-		let rem = s - base;
+	}
 
-		let result = if exp < 4 {
-			// incr of 1/8
-			let incr = base / 8;
-			let incrs = (rem + incr - 1) / incr;
-			exp * 8 + incrs
+	/// How many entries should be in a contents table whose items are `datum_size`?
+	pub fn contents_entries(&self, datum_size: DatumSize) -> usize {
+		if let Some(size) = self.size(datum_size) {
+			(self.table_bytes / size).min(self.table_entries).max(1)
 		} else {
-			// incr of 1/4
-			let incr = base / 4;
-			let incrs = (rem + incr - 1) / incr;
-			32 + ((exp - 4) * 4) + incrs
-		};
-
-		if result < MAX_SIZE as usize {
-			DatumSize::Size(result as u8)
-		} else {
-			DatumSize::Oversize
+			self.table_entries
 		}
 	}
 
-	/// How many entries should be in a contents table whose items are this size?
-	pub fn contents_entries(&self) -> usize {
-		// max total size per contents table = 2MB
-		// max number of items in contents table = 65536
-		if let Some(size) = self.size() {
-			(2048 * 1024 / size).min(65536).max(1)
-		} else {
-			65536
-		}
+	/// The most tables that size class `datum_size` may have, such that all of their items have a
+	/// `CompactContentAddress`.
+	pub fn max_tables(&self, datum_size: DatumSize) -> usize {
+		ADDRESSES_PER_CLASS / self.contents_entries(datum_size)
 	}
 
-	/// Total number of different sizes that are served by this. Only sensible for Sized.
-	pub fn size_range(&self) -> Option<usize> {
-		match *self {
+	/// Total number of different sizes that are served by `datum_size`. Only sensible for Sized.
+	pub fn size_range(&self, datum_size: DatumSize) -> Option<usize> {
+		match datum_size {
 			DatumSize::Oversize => None,
-			DatumSize::Size(size_class) => {
-				assert!(size_class < MAX_SIZE);
-				Some(if size_class == 0 {
-					33
-				} else {
-					if size_class <= 32 {
-						let exp = size_class as usize / 8;
-						let tweak = size_class as usize % 8;
-						let base = 32usize << exp;
-						if tweak == 0 {
-							base / 8 / 2
-						} else {
-							base / 8
-						}
-					} else {
-						let exp = size_class as usize / 4 - 4;
-						let tweak = size_class as usize % 4;
-						let base = 32usize << exp;
-						if tweak == 0 {
-							base / 4 / 2
-						} else {
-							base / 4
-						}
-					}
-				})
+			DatumSize::Size(0) => Some(self.sizes[0] as usize + 1),
+			DatumSize::Size(z) => Some(self.size(datum_size)? - self.sizes[z as usize - 1] as usize),
+		}
+	}
+
+	/// The scheme of at most `classes` classes which needs the least space to store values whose
+	/// sizes are distributed according to `histogram`, a list of value sizes along with how many
+	/// values there are of that size. Each class in use is taken to cost `class_overhead` bytes on
+	/// top of its items, which stops us using classes that would hardly be used.
+	///
+	/// Values larger than `max_size` are oversize and play no part. The largest class is always
+	/// `max_size` so that the new scheme doesn't change which values are oversize.
+	pub fn optimal(histogram: &[(u32, u32)], max_size: usize, classes: usize, class_overhead: usize) -> Self {
+		let classes = classes.min(MAX_SIZE as usize).max(1);
+		let max_size = max_size.min(u32::MAX as usize).max(1);
+		let mut counts = BTreeMap::<usize, usize>::new();
+		for &(size, count) in histogram {
+			let size = (size as usize).max(1);
+			if size <= max_size && count > 0 {
+				*counts.entry(size).or_default() += count as usize;
 			}
 		}
+		counts.entry(max_size).or_default();
+
+		// A class always ends at one of the sizes seen. `below[i]` is the number of values smaller
+		// than `sizes[i]`.
+		let sizes = counts.keys().cloned().collect::<Vec<_>>();
+		let below = std::iter::once(0)
+			.chain(counts.values().scan(0, |total, &count| { *total += count; Some(*total) }))
+			.collect::<Vec<_>>();
+		let n = sizes.len();
+		// The space taken by the values of sizes `sizes[j..=i]` in a class of size `sizes[i]`.
+		let cost = |j: usize, i: usize| sizes[i] * (below[i + 1] - below[j]);
+
+		// `best[i]` is the least space needed for the values up to `sizes[i]` using the number of
+		// classes we're currently considering, the largest of which is `sizes[i]`; `starts[m][i]`
+		// is the first size which falls into that largest class when using `m + 1` classes.
+		let mut best = (0..n).map(|i| cost(0, i)).collect::<Vec<_>>();
+		let mut starts = vec![vec![0; n]];
+		let mut chosen = (best[n - 1] + class_overhead, 1);
+		for m in 2..=classes.min(n) {
+			let mut next = vec![usize::MAX; n];
+			let mut start = vec![0; n];
+			// The start of the largest class never decreases as its end increases, so we can find
+			// the best start for each end by divide and conquer.
+			let mut work = vec![(m - 1, n - 1, m - 1, n - 1)];
+			while let Some((lo, hi, from, to)) = work.pop() {
+				let mid = (lo + hi) / 2;
+				let (total, j) = (from..=to.min(mid))
+					.map(|j| (best[j - 1].saturating_add(cost(j, mid)), j))
+					.min()
+					.expect("from <= mid; qed");
+				next[mid] = total;
+				start[mid] = j;
+				if mid > lo {
+					work.push((lo, mid - 1, from, j));
+				}
+				if mid < hi {
+					work.push((mid + 1, hi, j, to));
+				}
+			}
+			best = next;
+			starts.push(start);
+			let total = best[n - 1].saturating_add(class_overhead * m);
+			if total < chosen.0 {
+				chosen = (total, m);
+			}
+		}
+
+		let mut result = Vec::with_capacity(chosen.1);
+		let mut i = n - 1;
+		for m in (0..chosen.1).rev() {
+			result.push(sizes[i]);
+			i = starts[m][i].wrapping_sub(1);
+		}
+		result.reverse();
+		Self::new(result).expect("Sizes are increasing and at most MAX_SIZE; qed")
 	}
-}
 
-impl From<u8> for DatumSize {
-	fn from(x: u8) -> Self {
-		if x < MAX_SIZE {
-			DatumSize::Size(x)
-		} else {
-			DatumSize::Oversize
+	/// The space needed to store values whose sizes are distributed according to `histogram` (as
+	/// with `optimal`): each value takes the size of its class, each class in use costs
+	/// `class_overhead` and oversize values take only their own size.
+	pub fn footprint(&self, histogram: &[(u32, u32)], class_overhead: usize) -> usize {
+		let mut used = BTreeSet::new();
+		let mut total = 0;
+		for &(size, count) in histogram.iter().filter(|&&(_, count)| count > 0) {
+			let datum_size = self.nearest(size as usize);
+			if let Some(class_size) = self.size(datum_size) {
+				used.insert(datum_size);
+				total += class_size * count as usize;
+			} else {
+				total += size as usize * count as usize;
+			}
 		}
+		total + used.len() * class_overhead
 	}
 }
 
-impl From<DatumSize> for u8 {
-	fn from(x: DatumSize) -> u8 {
-		match x {
-			DatumSize::Oversize => MAX_SIZE,
-			DatumSize::Size(x) => x,
+/// The version of the encoding of a `SizeScheme`, with which it starts.
+const SCHEME_VERSION: u8 = 1;
+
+impl Encode for SizeScheme {
+	fn encode_to<O: codec::Output>(&self, dest: &mut O) {
+		SCHEME_VERSION.encode_to(dest);
+		self.sizes.encode_to(dest);
+		(self.table_bytes as u64).encode_to(dest);
+		(self.table_entries as u32).encode_to(dest);
+	}
+}
+
+impl Decode for SizeScheme {
+	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
+		if input.read_byte()? != SCHEME_VERSION {
+			return Err("Unknown size scheme version".into())
 		}
+		let sizes = Vec::<u32>::decode(input)?;
+		let table_bytes = u64::decode(input)?;
+		let table_entries = u32::decode(input)?;
+		Self::new(sizes.into_iter().map(|s| s as usize).collect())
+			.and_then(|scheme| scheme.with_geometry(table_bytes as usize, table_entries as usize))
+			.map_err(|_| "Invalid size scheme".into())
 	}
 }
 
 #[test]
 fn datum_size_works() {
-	assert_eq!(DatumSize::from(0).size().unwrap(), 32);
-	assert_eq!(DatumSize::from(1).size().unwrap(), 36);
-	assert_eq!(DatumSize::from(2).size().unwrap(), 40);
-	assert_eq!(DatumSize::from(7).size().unwrap(), 60);
-	assert_eq!(DatumSize::from(8).size().unwrap(), 64);
-	assert_eq!(DatumSize::from(9).size().unwrap(), 72);
-	assert_eq!(DatumSize::from(15).size().unwrap(), 120);
-	assert_eq!(DatumSize::from(16).size().unwrap(), 128);
-	assert_eq!(DatumSize::from(17).size().unwrap(), 144);
-	assert_eq!(DatumSize::from(24).size().unwrap(), 256);
-	assert_eq!(DatumSize::from(31).size().unwrap(), 480);
-	assert_eq!(DatumSize::from(32).size().unwrap(), 512);
-	assert_eq!(DatumSize::from(33).size().unwrap(), 640);
-	assert_eq!(DatumSize::from(34).size().unwrap(), 768);
-	assert_eq!(DatumSize::from(35).size().unwrap(), 896);
-	assert_eq!(DatumSize::from(36).size().unwrap(), 1_024);
-	assert_eq!(DatumSize::from(37).size().unwrap(), 1_280);
-	assert_eq!(DatumSize::from(38).size().unwrap(), 1_536);
-	assert_eq!(DatumSize::from(39).size().unwrap(), 1_792);
-	assert_eq!(DatumSize::from(40).size().unwrap(), 2_048);
-	assert_eq!(DatumSize::from(44).size().unwrap(), 4_096);
-	assert_eq!(DatumSize::from(48).size().unwrap(), 8_192);
-	assert_eq!(DatumSize::from(52).size().unwrap(), 16_384);
-	assert_eq!(DatumSize::from(56).size().unwrap(), 32_768);
-	assert_eq!(DatumSize::from(60).size().unwrap(), 65_536);
-	assert_eq!(DatumSize::from(62).size().unwrap(), 98_304);
-	assert_eq!(DatumSize::from(63).size(), None);
-
-	assert_eq!(DatumSize::nearest(0).size().unwrap(), 32);
-	assert_eq!(DatumSize::nearest(29).size().unwrap(), 32);
-	assert_eq!(DatumSize::nearest(30).size().unwrap(), 32);
-	assert_eq!(DatumSize::nearest(31).size().unwrap(), 32);
-	assert_eq!(DatumSize::nearest(32).size().unwrap(), 32);
-	assert_eq!(DatumSize::nearest(33).size().unwrap(), 36);
-	assert_eq!(DatumSize::nearest(34).size().unwrap(), 36);
-	assert_eq!(DatumSize::nearest(35).size().unwrap(), 36);
-	assert_eq!(DatumSize::nearest(36).size().unwrap(), 36);
-	assert_eq!(DatumSize::nearest(37).size().unwrap(), 40);
-	assert_eq!(DatumSize::nearest(38).size().unwrap(), 40);
-	assert_eq!(DatumSize::nearest(39).size().unwrap(), 40);
-	assert_eq!(DatumSize::nearest(40).size().unwrap(), 40);
-	assert_eq!(DatumSize::nearest(62).size().unwrap(), 64);
-	assert_eq!(DatumSize::nearest(63).size().unwrap(), 64);
-	assert_eq!(DatumSize::nearest(64).size().unwrap(), 64);
-	assert_eq!(DatumSize::nearest(65).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(66).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(67).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(68).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(69).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(70).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(71).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(72).size().unwrap(), 72);
-	assert_eq!(DatumSize::nearest(73).size().unwrap(), 80);
-
-	assert_eq!(DatumSize::nearest(480).size().unwrap(), 480);
-	assert_eq!(DatumSize::nearest(481).size().unwrap(), 512);
-	assert_eq!(DatumSize::nearest(512).size().unwrap(), 512);
-	assert_eq!(DatumSize::nearest(513).size().unwrap(), 640);
-	assert_eq!(DatumSize::nearest(640).size().unwrap(), 640);
-	assert_eq!(DatumSize::nearest(641).size().unwrap(), 768);
-
-	assert_eq!(DatumSize::nearest(98_303).size().unwrap(), 98_304);
-	assert_eq!(DatumSize::nearest(98_304).size().unwrap(), 98_304);
-	assert_eq!(DatumSize::nearest(98_305).size(), None);
+	let s = SizeScheme::default();
+	assert_eq!(s.size(DatumSize::from(0)).unwrap(), 32);
+	assert_eq!(s.size(DatumSize::from(1)).unwrap(), 36);
+	assert_eq!(s.size(DatumSize::from(2)).unwrap(), 40);
+	assert_eq!(s.size(DatumSize::from(7)).unwrap(), 60);
+	assert_eq!(s.size(DatumSize::from(8)).unwrap(), 64);
+	assert_eq!(s.size(DatumSize::from(9)).unwrap(), 72);
+	assert_eq!(s.size(DatumSize::from(15)).unwrap(), 120);
+	assert_eq!(s.size(DatumSize::from(16)).unwrap(), 128);
+	assert_eq!(s.size(DatumSize::from(17)).unwrap(), 144);
+	assert_eq!(s.size(DatumSize::from(24)).unwrap(), 256);
+	assert_eq!(s.size(DatumSize::from(31)).unwrap(), 480);
+	assert_eq!(s.size(DatumSize::from(32)).unwrap(), 512);
+	assert_eq!(s.size(DatumSize::from(33)).unwrap(), 640);
+	assert_eq!(s.size(DatumSize::from(34)).unwrap(), 768);
+	assert_eq!(s.size(DatumSize::from(35)).unwrap(), 896);
+	assert_eq!(s.size(DatumSize::from(36)).unwrap(), 1_024);
+	assert_eq!(s.size(DatumSize::from(37)).unwrap(), 1_280);
+	assert_eq!(s.size(DatumSize::from(38)).unwrap(), 1_536);
+	assert_eq!(s.size(DatumSize::from(39)).unwrap(), 1_792);
+	assert_eq!(s.size(DatumSize::from(40)).unwrap(), 2_048);
+	assert_eq!(s.size(DatumSize::from(44)).unwrap(), 4_096);
+	assert_eq!(s.size(DatumSize::from(48)).unwrap(), 8_192);
+	assert_eq!(s.size(DatumSize::from(52)).unwrap(), 16_384);
+	assert_eq!(s.size(DatumSize::from(56)).unwrap(), 32_768);
+	assert_eq!(s.size(DatumSize::from(60)).unwrap(), 65_536);
+	assert_eq!(s.size(DatumSize::from(62)).unwrap(), 98_304);
+	assert_eq!(s.size(DatumSize::from(63)), None);
+
+	assert_eq!(s.size(s.nearest(0)).unwrap(), 32);
+	assert_eq!(s.size(s.nearest(29)).unwrap(), 32);
+	assert_eq!(s.size(s.nearest(30)).unwrap(), 32);
+	assert_eq!(s.size(s.nearest(31)).unwrap(), 32);
+	assert_eq!(s.size(s.nearest(32)).unwrap(), 32);
+	assert_eq!(s.size(s.nearest(33)).unwrap(), 36);
+	assert_eq!(s.size(s.nearest(34)).unwrap(), 36);
+	assert_eq!(s.size(s.nearest(35)).unwrap(), 36);
+	assert_eq!(s.size(s.nearest(36)).unwrap(), 36);
+	assert_eq!(s.size(s.nearest(37)).unwrap(), 40);
+	assert_eq!(s.size(s.nearest(38)).unwrap(), 40);
+	assert_eq!(s.size(s.nearest(39)).unwrap(), 40);
+	assert_eq!(s.size(s.nearest(40)).unwrap(), 40);
+	assert_eq!(s.size(s.nearest(62)).unwrap(), 64);
+	assert_eq!(s.size(s.nearest(63)).unwrap(), 64);
+	assert_eq!(s.size(s.nearest(64)).unwrap(), 64);
+	assert_eq!(s.size(s.nearest(65)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(66)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(67)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(68)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(69)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(70)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(71)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(72)).unwrap(), 72);
+	assert_eq!(s.size(s.nearest(73)).unwrap(), 80);
+
+	assert_eq!(s.size(s.nearest(480)).unwrap(), 480);
+	assert_eq!(s.size(s.nearest(481)).unwrap(), 512);
+	assert_eq!(s.size(s.nearest(512)).unwrap(), 512);
+	assert_eq!(s.size(s.nearest(513)).unwrap(), 640);
+	assert_eq!(s.size(s.nearest(640)).unwrap(), 640);
+	assert_eq!(s.size(s.nearest(641)).unwrap(), 768);
+
+	assert_eq!(s.size(s.nearest(98_303)).unwrap(), 98_304);
+	assert_eq!(s.size(s.nearest(98_304)).unwrap(), 98_304);
+	assert_eq!(s.size(s.nearest(98_305)), None);
+}
+
+#[test]
+fn custom_size_scheme_works() {
+	assert!(SizeScheme::new(vec![]).is_err());
+	assert!(SizeScheme::new(vec![16, 16]).is_err());
+	assert!(SizeScheme::new(vec![1; 64]).is_err());
+	let s = SizeScheme::new(vec![16, 65, 69, 100]).unwrap();
+	assert_eq!(s.nearest(0), DatumSize::Size(0));
+	assert_eq!(s.nearest(16), DatumSize::Size(0));
+	assert_eq!(s.nearest(17), DatumSize::Size(1));
+	assert_eq!(s.nearest(66), DatumSize::Size(2));
+	assert_eq!(s.nearest(100), DatumSize::Size(3));
+	assert_eq!(s.nearest(101), DatumSize::Oversize);
+	assert_eq!(s.size_range(DatumSize::Size(0)), Some(17));
+	assert_eq!(s.size_range(DatumSize::Size(2)), Some(4));
+	assert_eq!(s.size_range(DatumSize::Oversize), None);
+	assert_eq!(SizeScheme::decode(&mut &s.encode()[..]).unwrap(), s);
+	assert!(SizeScheme::decode(&mut &vec![20u32, 10].encode()[..]).is_err());
+}
+
+#[test]
+fn optimal_size_scheme_works() {
+	let histogram = [(65, 2_500_000), (69, 5_600_000), (100, 10), (300, 1)];
+	let scheme = SizeScheme::optimal(&histogram, 200, 3, 0);
+	assert_eq!(scheme.sizes().collect::<Vec<_>>(), vec![65, 69, 200]);
+	assert_eq!(scheme.footprint(&histogram, 0), 65 * 2_500_000 + 69 * 5_600_000 + 200 * 10 + 300);
+	let scheme = SizeScheme::optimal(&histogram, 200, 4, 0);
+	assert_eq!(scheme.sizes().collect::<Vec<_>>(), vec![65, 69, 100, 200]);
+	// Another class for the 100-byte values would save only 1000 bytes.
+	let scheme = SizeScheme::optimal(&histogram, 200, 4, 2000);
+	assert_eq!(scheme.sizes().collect::<Vec<_>>(), vec![65, 69, 200]);
+	let scheme = SizeScheme::optimal(&histogram, 200, 1, 0);
+	assert_eq!(scheme.sizes().collect::<Vec<_>>(), vec![200]);
+
+	let default = SizeScheme::default();
+	let scheme = SizeScheme::optimal(&histogram, default.max_size(), 63, 0);
+	assert_eq!(scheme.max_size(), default.max_size());
+	assert!(scheme.footprint(&histogram, 0) < default.footprint(&histogram, 0));
+}
+
+#[test]
+fn small_values_scheme_works() {
+	let s = SizeScheme::small_values();
+	assert_eq!(s.classes(), 63);
+	assert_eq!(s.max_size(), SizeScheme::default().max_size());
+	assert_eq!(s.size(s.nearest(1)), Some(8));
+	assert_eq!(s.size(s.nearest(9)), Some(16));
+	assert_eq!(s.size(s.nearest(24)), Some(24));
+	assert_eq!(s.size(s.nearest(25)), Some(32));
+	assert_eq!(s.size(s.nearest(69)), Some(72));
+	assert_eq!(s.size(s.nearest(32_769)), Some(49_152));
+	assert_eq!(s.size(s.nearest(65_537)), Some(98_304));
+	assert_eq!(s.size_range(s.nearest(1)), Some(9));
+}
+
+#[test]
+fn size_scheme_encoding_works() {
+	let scheme = SizeScheme::new(vec![16, 65, 69]).unwrap().with_geometry(4 * 1024 * 1024, 1024).unwrap();
+	assert_eq!(SizeScheme::decode(&mut &scheme.encode()[..]).unwrap(), scheme);
+	assert!(SizeScheme::decode(&mut &[2u8][..]).is_err());
+}
+
+#[test]
+fn table_geometry_works() {
+	assert!(SizeScheme::default().with_geometry(0, 1).is_err());
+	assert!(SizeScheme::default().with_geometry(1, 0).is_err());
+	assert!(SizeScheme::default().with_geometry(1, MAX_TABLE_ENTRIES + 1).is_err());
+	let s = SizeScheme::default().with_geometry(64 * 1024 * 1024, 1 << 20).unwrap();
+	assert_eq!(s.contents_entries(DatumSize::Size(0)), 1 << 20);
+	assert_eq!(s.contents_entries(s.nearest(72)), 64 * 1024 * 1024 / 72);
+	assert_eq!(s.contents_entries(s.nearest(98_304)), 682);
+	assert_eq!(s.contents_entries(DatumSize::Oversize), 1 << 20);
+	assert_eq!(s.max_tables(DatumSize::Size(0)), 64);
+	assert_eq!(s.max_tables(s.nearest(98_304)), (1 << 26) / 682);
+	assert_eq!(SizeScheme::decode(&mut &s.encode()[..]).unwrap(), s);
 }
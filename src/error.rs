@@ -13,6 +13,39 @@ pub enum Error {
 	#[display(fmt="Unsupported version")]
 	UnsupportedVersion,
 
+	/// A content table has a different layout to that expected.
+	#[display(fmt="Incompatible content table: {}", _0)]
+	#[from(ignore)]
+	IncompatibleTable(String),
+
+	/// The key type is not that with which the database was created.
+	#[display(fmt="Incompatible key type: {}", _0)]
+	#[from(ignore)]
+	IncompatibleKey(String),
+
+	/// The options are not valid for the key type.
+	#[display(fmt="Bad options: {}", _0)]
+	#[from(ignore)]
+	BadOptions(String),
+
+	/// An export stream is corrupt or of an unsupported version.
+	#[display(fmt="Bad export stream: {}", _0)]
+	#[from(ignore)]
+	BadExport(String),
+
+	/// An iteration cursor was taken before the database was repacked, or before an item which
+	/// it had passed was removed or moved.
+	#[display(fmt="Cursor is stale")]
+	StaleCursor,
+
+	/// The size scheme is not valid.
+	#[display(fmt="Bad size scheme")]
+	BadSizeScheme,
+
+	/// A size class has used all of the content addresses available to it.
+	#[display(fmt="Content address space full")]
+	AddressSpaceFull,
+
 	/// The index has become full.
 	#[display(fmt="Index full")]
 	IndexFull,
@@ -0,0 +1,202 @@
+use std::hash::Hasher;
+use std::io::{Read, Write};
+use parity_scale_codec::{self as codec, Encode, Decode, Compact};
+use twox_hash::XxHash64;
+use crate::table::RefCount;
+use crate::Error;
+
+/// Identifies a stream as a SubDB export.
+const EXPORT_MAGIC: [u8; 4] = *b"SBDX";
+
+/// The version of the export stream format.
+const EXPORT_VERSION: u32 = 1;
+
+/// An item in an export stream.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ExportRecord {
+	pub key: Vec<u8>,
+	pub ref_count: RefCount,
+	pub value: Vec<u8>,
+}
+
+/// Passes everything written on to `inner`, keeping a checksum of it.
+struct ChecksumWriter<W> {
+	inner: W,
+	checksum: XxHash64,
+	error: Option<std::io::Error>,
+}
+
+impl<W: Write> codec::Output for ChecksumWriter<W> {
+	fn write(&mut self, bytes: &[u8]) {
+		if self.error.is_none() {
+			self.checksum.write(bytes);
+			self.error = self.inner.write_all(bytes).err();
+		}
+	}
+}
+
+/// Passes on everything read from `inner`, keeping a checksum of it.
+struct ChecksumReader<R> {
+	inner: R,
+	checksum: XxHash64,
+}
+
+impl<R: Read> codec::Input for ChecksumReader<R> {
+	fn remaining_len(&mut self) -> Result<Option<usize>, codec::Error> {
+		Ok(None)
+	}
+
+	fn read(&mut self, into: &mut [u8]) -> Result<(), codec::Error> {
+		self.inner.read_exact(into).map_err(|_| "Unexpected end of export stream")?;
+		self.checksum.write(into);
+		Ok(())
+	}
+}
+
+/// Writes an export stream: a header giving the format version and the key length, then each
+/// item as a SCALE-encoded record and finally the number of items along with a checksum of
+/// everything before it. The stream depends on neither the size scheme nor the index of the
+/// database it came from.
+pub struct ExportWriter<W: Write> {
+	output: ChecksumWriter<W>,
+	key_length: usize,
+	count: u64,
+}
+
+impl<W: Write> ExportWriter<W> {
+	/// Begin writing a stream of items with keys of `key_length` bytes to `output`.
+	pub fn new(output: W, key_length: usize) -> Result<Self, Error> {
+		let mut output = ChecksumWriter { inner: output, checksum: XxHash64::with_seed(0), error: None };
+		(EXPORT_MAGIC, EXPORT_VERSION, key_length as u32).encode_to(&mut output);
+		let mut writer = Self { output, key_length, count: 0 };
+		writer.check()?;
+		Ok(writer)
+	}
+
+	fn check(&mut self) -> Result<(), Error> {
+		self.output.error.take().map_or(Ok(()), |e| Err(e.into()))
+	}
+
+	/// Write an item.
+	pub fn write(&mut self, key: &[u8], ref_count: RefCount, value: &[u8]) -> Result<(), Error> {
+		if key.len() != self.key_length {
+			return Err(Error::BadExport(format!("key of {} bytes in an export of keys of {} bytes",
+				key.len(), self.key_length)))
+		}
+		1u8.encode_to(&mut self.output);
+		codec::Output::write(&mut self.output, key);
+		ref_count.encode_to(&mut self.output);
+		value.encode_to(&mut self.output);
+		self.count += 1;
+		self.check()
+	}
+
+	/// End the stream, returning the number of items written.
+	pub fn finish(mut self) -> Result<u64, Error> {
+		0u8.encode_to(&mut self.output);
+		Compact(self.count).encode_to(&mut self.output);
+		let checksum = self.output.checksum.finish();
+		self.check()?;
+		self.output.inner.write_all(&checksum.to_le_bytes())?;
+		self.output.inner.flush()?;
+		Ok(self.count)
+	}
+}
+
+/// Reads an export stream, as written by `ExportWriter`.
+pub struct ExportReader<R: Read> {
+	input: ChecksumReader<R>,
+	key_length: usize,
+	count: u64,
+	done: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+	/// Begin reading the stream in `input`.
+	pub fn new(input: R) -> Result<Self, Error> {
+		let mut input = ChecksumReader { inner: input, checksum: XxHash64::with_seed(0) };
+		let magic = <[u8; 4]>::decode(&mut input).map_err(|_| Error::BadExport("too short".into()))?;
+		if magic != EXPORT_MAGIC {
+			return Err(Error::BadExport("not an export".into()))
+		}
+		let version = u32::decode(&mut input).map_err(|_| Error::BadExport("too short".into()))?;
+		if version != EXPORT_VERSION {
+			return Err(Error::BadExport(format!("unsupported version {}", version)))
+		}
+		let key_length = u32::decode(&mut input).map_err(|_| Error::BadExport("too short".into()))? as usize;
+		Ok(Self { input, key_length, count: 0, done: false })
+	}
+
+	/// The length of the keys of the items in the stream.
+	pub fn key_length(&self) -> usize {
+		self.key_length
+	}
+
+	/// Read the next item, or `None` if the stream is at its end. The checksum is checked only
+	/// once the end is reached, so items read from a corrupt stream are only known to be good
+	/// once this has returned `Ok(None)`.
+	pub fn next_record(&mut self) -> Result<Option<ExportRecord>, Error> {
+		if self.done {
+			return Ok(None)
+		}
+		let count = self.count;
+		let bad = |e: codec::Error| Error::BadExport(format!("item {}: {}", count, e));
+		match u8::decode(&mut self.input).map_err(bad)? {
+			1 => {
+				let mut key = vec![0; self.key_length];
+				codec::Input::read(&mut self.input, &mut key).map_err(bad)?;
+				let ref_count = RefCount::decode(&mut self.input).map_err(bad)?;
+				let value = Vec::<u8>::decode(&mut self.input).map_err(bad)?;
+				self.count += 1;
+				Ok(Some(ExportRecord { key, ref_count, value }))
+			}
+			0 => {
+				let count = Compact::<u64>::decode(&mut self.input).map_err(bad)?.0;
+				let expected = self.input.checksum.finish();
+				let mut checksum = [0u8; 8];
+				self.input.inner.read_exact(&mut checksum)
+					.map_err(|_| Error::BadExport("too short".into()))?;
+				if count != self.count {
+					return Err(Error::BadExport(format!("{} items read but {} written", self.count, count)))
+				}
+				if u64::from_le_bytes(checksum) != expected {
+					return Err(Error::BadExport("checksum mismatch".into()))
+				}
+				self.done = true;
+				Ok(None)
+			}
+			_ => Err(Error::BadExport(format!("item {}: bad tag", self.count))),
+		}
+	}
+}
+
+#[test]
+fn export_stream_works() {
+	let mut stream = vec![];
+	let mut writer = ExportWriter::new(&mut stream, 2).unwrap();
+	writer.write(&[1, 2], 3, b"Hello").unwrap();
+	writer.write(&[3, 4], 1, &[42u8; 1000][..]).unwrap();
+	assert!(matches!(writer.write(&[5], 1, b"Short key"), Err(Error::BadExport(_))));
+	assert_eq!(writer.finish().unwrap(), 2);
+
+	let read = |stream: &[u8]| -> Result<Vec<ExportRecord>, Error> {
+		let mut reader = ExportReader::new(stream)?;
+		assert_eq!(reader.key_length(), 2);
+		let mut records = vec![];
+		while let Some(record) = reader.next_record()? {
+			records.push(record);
+		}
+		Ok(records)
+	};
+	assert_eq!(read(&stream).unwrap(), vec![
+		ExportRecord { key: vec![1, 2], ref_count: 3, value: b"Hello".to_vec() },
+		ExportRecord { key: vec![3, 4], ref_count: 1, value: vec![42u8; 1000] },
+	]);
+
+	let mut corrupt = stream.clone();
+	corrupt[20] ^= 1;
+	assert!(matches!(read(&corrupt), Err(Error::BadExport(_))));
+	assert!(matches!(read(&stream[..stream.len() - 1]), Err(Error::BadExport(_))));
+	assert!(matches!(read(&stream[..30]), Err(Error::BadExport(_))));
+	assert!(matches!(read(b"SBDB"), Err(Error::BadExport(_))));
+}
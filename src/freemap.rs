@@ -1,12 +1,28 @@
-pub struct FreeMap<'a>(&'a mut [u64]);
-impl<'a> FreeMap<'a> {
-	pub fn new(data: &'a mut [u64]) -> Self {
-		Self(data)
+/// A bitmap recording which of a number of items are taken, along with how many are.
+#[derive(Clone, Default, Debug)]
+pub struct FreeMap(Vec<u64>, usize);
+impl FreeMap {
+	pub fn new(data: Vec<u64>) -> Self {
+		let total = data.iter().map(|&x| x.count_ones() as usize).sum();
+		Self(data, total)
+	}
+
+	/// Create a new instance with room for `count` items, none of them taken.
+	pub fn with_capacity(count: usize) -> Self {
+		Self::new(vec![0; (count + 63) >> 6])
+	}
+
+	/// Make room for at least `count` items; any new ones are not taken.
+	pub fn reserve(&mut self, count: usize) {
+		let words = (count + 63) >> 6;
+		if words > self.0.len() {
+			self.0.resize(words, 0);
+		}
 	}
 
 	pub fn set(&mut self, i: usize) {
 		if !self.get(i) {
-			self.0[i / 64] |= (1u64 << (i % 64) as u64);
+			self.0[i / 64] |= 1u64 << (i % 64) as u64;
 			self.1 += 1;
 		}
 	}
@@ -19,18 +35,82 @@ impl<'a> FreeMap<'a> {
 	}
 
 	pub fn get(&self, i: usize) -> bool {
-		(self.0[i / 64] & (1u64 << (i % 64) as u64)) != 0
+		matches!(self.0.get(i / 64), Some(&w) if w & (1u64 << (i % 64) as u64) != 0)
 	}
 
+	/// The first item of the first `count` which is not taken.
 	pub fn next_free(&self, count: usize) -> Option<usize> {
-		self.0.iter()
-			.enumerate()
-			.find(|(_, v)| v != u64::max_value())
-			.map(|(i, v)| (!v).leading_zeros() as usize + i * 64)
-			.filter(|&x| x < count)
+		self.find(0, count, true)
+	}
+
+	/// The first item from `from` and of the first `count` which is taken.
+	pub fn next_set(&self, from: usize, count: usize) -> Option<usize> {
+		self.find(from, count, false)
+	}
+
+	/// All items of the first `count` which are not taken, in order.
+	pub fn iter_free(&self, count: usize) -> impl Iterator<Item = usize> + '_ {
+		self.iter(count, true)
+	}
+
+	/// All items of the first `count` which are taken, in order.
+	pub fn iter_set(&self, count: usize) -> impl Iterator<Item = usize> + '_ {
+		self.iter(count, false)
+	}
+
+	fn iter(&self, count: usize, free: bool) -> impl Iterator<Item = usize> + '_ {
+		let mut from = 0;
+		std::iter::from_fn(move || {
+			let i = self.find(from, count, free)?;
+			from = i + 1;
+			Some(i)
+		})
+	}
+
+	/// The first item from `from` and of the first `count` which is not taken if `free`, or which
+	/// is taken if not. Whole words are skipped at a time.
+	fn find(&self, from: usize, count: usize, free: bool) -> Option<usize> {
+		let mut w = from / 64;
+		// Ignore any items before `from` in its word.
+		let mut mask = !0u64 << (from % 64);
+		while w * 64 < count {
+			let word = if free { !self.word(w) } else { self.word(w) } & mask;
+			if word != 0 {
+				return Some(w * 64 + word.trailing_zeros() as usize).filter(|&i| i < count)
+			}
+			mask = !0;
+			w += 1;
+		}
+		None
 	}
 
 	pub fn total_set(&self) -> usize {
-		self.0.iter().map(|&x| x.count_ones() as usize).sum()
+		self.1
+	}
+
+	/// The `w`th word of the bitmap, covering items `w * 64` to `w * 64 + 63`.
+	pub fn word(&self, w: usize) -> u64 {
+		self.0.get(w).cloned().unwrap_or(0)
 	}
 }
+
+#[test]
+fn free_map_works() {
+	let mut m = FreeMap::with_capacity(130);
+	assert_eq!(m.next_free(130), Some(0));
+	(0..70).for_each(|i| m.set(i));
+	assert_eq!(m.total_set(), 70);
+	assert_eq!(m.next_free(130), Some(70));
+	assert_eq!(m.next_free(70), None);
+	m.clear(3);
+	m.clear(3);
+	assert_eq!(m.total_set(), 69);
+	assert_eq!(m.next_free(130), Some(3));
+	assert_eq!(m.iter_free(72).collect::<Vec<_>>(), vec![3, 70, 71]);
+	assert_eq!(m.next_set(3, 130), Some(4));
+	assert_eq!(m.next_set(70, 130), None);
+	assert_eq!(m.iter_set(130).count(), 69);
+	assert_eq!(m.iter_free(200).count(), 131);
+	m.reserve(200);
+	assert!(!m.get(199));
+}
@@ -1,14 +1,16 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::fs::{OpenOptions};
 use std::fmt::Debug;
 use std::convert::TryInto;
-use memmap::MmapMut;
+use memmap::{MmapMut, MmapOptions};
 use parity_scale_codec::Codec;
 use smallvec::SmallVec;
 use log::trace;
 
 use crate::types::{KeyType, SimpleWriter, EncodedSize};
 use crate::index_item::{IndexItem, IndexEntry};
+use crate::stats::IndexStats;
 use crate::Error;
 
 pub struct Index<K, V> {
@@ -28,6 +30,31 @@ pub struct Index<K, V> {
 	_dummy: std::marker::PhantomData<(K, V)>,
 }
 
+/// Check that an index with `key_bytes` of key material and `index_bits` can be used with keys of
+/// `key_length` bytes.
+pub fn check_geometry(key_bytes: usize, index_bits: usize, key_length: usize) -> Result<(), Error> {
+	let fail = |what: String| Err(Error::BadOptions(what));
+	// How much of the key `index_suffix_of` reads to find the index.
+	let index_read = match index_bits {
+		0 => 0,
+		1..=8 => 1,
+		9..=16 => 2,
+		17..=32 => 4,
+		33..=64 => 8,
+		_ => return fail(format!("index of {} bits is more than 64", index_bits)),
+	};
+	if key_bytes < index_bits / 8 {
+		return fail(format!("{} key bytes is too few for an index of {} bits", key_bytes, index_bits))
+	}
+	if key_bytes > key_length {
+		return fail(format!("{} key bytes is more than the key length of {}", key_bytes, key_length))
+	}
+	if index_read > key_length {
+		return fail(format!("an index of {} bits needs keys of at least {} bytes, not {}", index_bits, index_read, key_length))
+	}
+	Ok(())
+}
+
 impl<K, V> Drop for Index<K, V> {
 	fn drop(&mut self) {
 		self.commit();
@@ -68,6 +95,28 @@ impl<K: KeyType, V: Codec + EncodedSize + Debug> Index<K, V> {
 		})
 	}
 
+	/// Open an existing index without ever writing to it. Changes made to it stay in memory.
+	pub fn open_read_only(filename: PathBuf, key_bytes: usize, index_bits: usize) -> Result<Self, Error> {
+		let file = OpenOptions::new().read(true).open(&filename)?;
+
+		let index_full_bytes = index_bits / 8;
+		let suffix_len = key_bytes - index_full_bytes;
+		let index_mask = ((1u128 << index_bits as u128) - 1) as usize;
+		let item_size = 2 + 1 + V::encoded_size() + suffix_len;
+		let item_count = 1 << index_bits;
+
+		if file.metadata()?.len() != (item_count * item_size) as u64 {
+			return Err(Error::BadMetadata)
+		}
+		let index = unsafe { MmapOptions::new().map_copy(&file)? };
+
+		Ok(Self {
+			index, key_bytes, suffix_len, index_mask, skipped_count_watermark: 0,
+			key_correction_watermark: 0,
+			index_bits, index_full_bytes, item_size, item_count, _dummy: Default::default()
+		})
+	}
+
 	/// Open a database if it already exists and create a new one if not.
 	pub fn anonymous(key_bytes: usize, index_bits: usize) -> Result<Self, Error> {
 		let index_full_bytes = index_bits / 8;
@@ -335,16 +384,156 @@ impl<K: KeyType, V: Codec + EncodedSize + Debug> Index<K, V> {
 		Ok(result)
 	}
 
+	/// Call `f` for each entry in the index, in index order, with its position, the part of its
+	/// key which the index holds, how far it is from the position at which it would ideally be and
+	/// its value.
+	pub fn for_each_entry(&self, mut f: impl FnMut(usize, &[u8], usize, &V)) {
+		for i in 0..self.item_count {
+			if let Some(entry) = self.read_item(i).maybe_entry {
+				let index = (i + self.item_count - entry.key_correction) % self.item_count;
+				f(i, &self.key_prefix(index, &entry.key_suffix), entry.key_correction, &entry.address);
+			}
+		}
+	}
+
+	/// The greatest distance of any entry from the position at which it would ideally be.
+	pub fn max_key_correction(&self) -> usize {
+		(0..self.item_count)
+			.filter_map(|i| self.read_item(i).maybe_entry)
+			.map(|entry| entry.key_correction)
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// Call `f` with the value of each entry which would ideally be at `position`. As when looking
+	/// an item up, they're found by probing onwards for as long as an entry has been skipped over,
+	/// but no further than `max_correction`.
+	pub fn for_each_entry_at(&self, position: usize, max_correction: usize, mut f: impl FnMut(&V)) {
+		for correction in 0..=max_correction.min(self.item_count - 1) {
+			let item = self.read_item((position + correction) % self.item_count);
+			match item.maybe_entry {
+				Some(entry) if entry.key_correction == correction => f(&entry.address),
+				_ => {}
+			}
+			if item.skipped_count == 0 {
+				break
+			}
+		}
+	}
+
+	/// The value of every entry, along with its key as found by `key_of` and the key's place in an
+	/// order which is the same whatever the geometry of the index: the first eight bytes of the
+	/// key, read little-endian with their bits reversed, and then the whole key.
+	///
+	/// The ideal position of a key is the low bits of that little-endian number, so visiting the
+	/// positions in bit-reversed order visits keys in order of the first bits of their place. Only
+	/// the entries of each position need then be sorted.
+	pub fn in_key_order<'a, T: AsRef<[u8]> + 'a>(
+		&'a self,
+		key_of: impl Fn(&V) -> T + 'a,
+	) -> impl Iterator<Item = (u64, T, V)> + 'a where V: Clone {
+		let index_bits = self.item_count.trailing_zeros();
+		let max_correction = self.max_key_correction();
+		let mut visited = 0;
+		let mut group = vec![].into_iter();
+		std::iter::from_fn(move || loop {
+			if let Some(item) = group.next() {
+				return Some(item)
+			}
+			if visited == self.item_count {
+				return None
+			}
+			let position = match index_bits {
+				0 => 0,
+				bits => ((visited as u64).reverse_bits() >> (64 - bits)) as usize,
+			};
+			visited += 1;
+			let mut entries = vec![];
+			self.for_each_entry_at(position, max_correction, |value| {
+				let key = key_of(value);
+				let mut first = [0u8; 8];
+				let len = key.as_ref().len().min(8);
+				first[..len].copy_from_slice(&key.as_ref()[..len]);
+				entries.push((u64::from_le_bytes(first).reverse_bits(), key, value.clone()));
+			});
+			entries.sort_by(|(a, key, _), (b, other_key, _)| (a, key.as_ref()).cmp(&(b, other_key.as_ref())));
+			group = entries.into_iter();
+		})
+	}
+
+	/// The length of each entry of the index.
+	pub fn entry_size(&self) -> usize {
+		self.item_size
+	}
+
+	/// The number of positions in the index.
+	pub fn len(&self) -> usize {
+		self.item_count
+	}
+
+	/// The value of the entry at `position`, if there is one.
+	pub fn value_at(&self, position: usize) -> Option<V> {
+		self.read_item(position).maybe_entry.map(|entry| entry.address)
+	}
+
 	pub fn next_size(&self) -> (usize, usize) {
 		let index_bits = self.index_bits + 1;
 		let key_bytes = self.key_bytes.max((self.index_bits + 7) / 8);
 		(key_bytes, index_bits)
 	}
 
-	pub fn take_watermarks(&mut self) -> (u8, usize) {
-		let r = (self.skipped_count_watermark, self.key_correction_watermark);
+	/// The greatest skipped count and key correction caused by an insertion since the watermarks
+	/// were last taken.
+	pub fn watermarks(&self) -> (u8, usize) {
+		(self.skipped_count_watermark, self.key_correction_watermark)
+	}
+
+	/// Start the watermarks afresh.
+	pub fn reset_watermarks(&mut self) {
 		self.skipped_count_watermark = 0;
 		self.key_correction_watermark = 0;
-		r
+	}
+
+	/// The occupancy, key corrections, skipped counts, clustering and watermarks of the index. The
+	/// triggers are left for the caller to fill in.
+	pub fn stats(&self) -> IndexStats {
+		let mut occupied = 0;
+		let mut key_corrections = BTreeMap::new();
+		let mut skipped_counts = BTreeMap::new();
+		let mut longest_cluster = 0;
+		let mut first_cluster = None;
+		let mut cluster = 0;
+		for i in 0..self.item_count {
+			let item = self.read_item(i);
+			if item.skipped_count > 0 {
+				*skipped_counts.entry(item.skipped_count).or_insert(0) += 1;
+			}
+			if let Some(entry) = item.maybe_entry {
+				occupied += 1;
+				*key_corrections.entry(entry.key_correction).or_insert(0) += 1;
+				cluster += 1;
+			} else {
+				first_cluster.get_or_insert(cluster);
+				longest_cluster = longest_cluster.max(cluster);
+				cluster = 0;
+			}
+		}
+		// The index wraps around, so a cluster at the end carries on into any at the start.
+		longest_cluster = match first_cluster {
+			Some(first) => longest_cluster.max(cluster + first),
+			None => cluster,
+		};
+		IndexStats {
+			key_bytes: self.key_bytes,
+			index_bits: self.index_bits,
+			slots: self.item_count,
+			occupied,
+			key_corrections: key_corrections.into_iter().collect(),
+			max_skipped_count: skipped_counts.keys().last().cloned().unwrap_or(0),
+			skipped_counts: skipped_counts.into_iter().collect(),
+			longest_cluster,
+			watermarks: self.watermarks(),
+			triggers: (0, 0),
+		}
 	}
 }
@@ -0,0 +1,265 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::content::Content;
+use crate::content_address::{CompactContentAddress, ContentAddress};
+use crate::datum_size::{DatumSize, SizeScheme};
+use crate::export::ExportWriter;
+use crate::index::{Index, check_geometry};
+use crate::metadata::{Metadata, MetadataV2, Version, SUPPORTED_FEATURES};
+use crate::stats::{ClassStats, IndexStats, Stats};
+use crate::table::{RawItem, RawTable, TableItemCount, TableItemIndex};
+use crate::Error;
+
+/// A summary of one content table.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TableSummary {
+	/// The size class of the table's items.
+	pub size_class: u8,
+	/// Which of the tables of its size class it is.
+	pub table_index: usize,
+	/// The length of the value of each item, or zero if the items are oversize.
+	pub value_size: usize,
+	/// The number of items that the table may hold.
+	pub total: TableItemCount,
+	/// The number of items that the table holds.
+	pub used: TableItemCount,
+	/// The number of slots that have ever been allocated.
+	pub touched: TableItemCount,
+	/// The number of bytes that the table takes on disk.
+	pub bytes_used: usize,
+}
+
+/// A read-only view of a database which needn't know its key type. The key length is read from
+/// the metadata, or failing that from the content tables, and keys are handled as byte slices.
+///
+/// Nothing is ever written to the database, not even to upgrade old metadata, so it's fine to
+/// inspect a database written by a newer build, as long as the formats are understood.
+pub struct Inspector {
+	metadata: MetadataV2,
+	version: Version,
+	key_length: usize,
+	index: Index<Vec<u8>, CompactContentAddress>,
+	tables: Vec<Vec<RawTable>>,
+}
+
+impl Inspector {
+	/// Open the database in `path` for inspection.
+	pub fn open(path: &Path) -> Result<Self, Error> {
+		let (metadata, version) = MetadataV2::read_only(path)?.ok_or(Error::BadMetadata)?;
+		if metadata.features & !SUPPORTED_FEATURES != 0 {
+			return Err(Error::UnsupportedVersion)
+		}
+		let content_path = metadata.content_path(path);
+		let oversize_path = metadata.oversize_path(path);
+
+		let tables = (0u8..64).map(|size| (0usize..)
+			.map(|table_index| Content::<Vec<u8>>::table_path_in(&content_path, &oversize_path, size, table_index))
+			.take_while(|table_path| table_path.is_file())
+			.map(|table_path| {
+				let table = RawTable::open(table_path.clone())?;
+				if table.datum_size() != DatumSize::from(size) {
+					return Err(Error::IncompatibleTable(format!("{}: wrong size class", table_path.display())))
+				}
+				Ok(table)
+			})
+			.collect::<Result<Vec<_>, _>>()
+		).collect::<Result<Vec<_>, _>>()?;
+
+		let key_length = match metadata.key_length {
+			Some(key_length) => key_length as usize,
+			None => tables.iter().flatten().next()
+				.ok_or_else(|| Error::IncompatibleKey("key length neither recorded nor in any table".into()))?
+				.key_size(),
+		};
+		for (size, tables) in tables.iter().enumerate() {
+			for (table_index, table) in tables.iter().enumerate() {
+				if table.key_size() != key_length {
+					let table_path = Content::<Vec<u8>>::table_path_in(&content_path, &oversize_path, size as u8, table_index);
+					return Err(Error::IncompatibleTable(format!("{}: key length is {} but expected {}",
+						table_path.display(), table.key_size(), key_length)))
+				}
+			}
+		}
+		check_geometry(metadata.key_bytes, metadata.index_bits, key_length)?;
+
+		let index = Index::open_read_only(
+			metadata.index_path(path).join("index.subdb"),
+			metadata.key_bytes,
+			metadata.index_bits,
+		)?;
+
+		Ok(Self { metadata, version, key_length, index, tables })
+	}
+
+	/// The version of the metadata format in which the database was written.
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	/// The length of the database's keys.
+	pub fn key_length(&self) -> usize {
+		self.key_length
+	}
+
+	/// The number of bytes of each key held in the index and the number of bits of it used to
+	/// position it there.
+	pub fn index_geometry(&self) -> (usize, usize) {
+		(self.metadata.key_bytes, self.metadata.index_bits)
+	}
+
+	/// The identifier of the hasher from which the keys come, if one was recorded.
+	pub fn hasher(&self) -> Option<&str> {
+		self.metadata.hasher.as_deref()
+	}
+
+	/// The size scheme of the content tables.
+	pub fn size_scheme(&self) -> &SizeScheme {
+		&self.metadata.size_scheme
+	}
+
+	/// A summary of each content table, in order of size class and then table.
+	pub fn tables(&self) -> Vec<TableSummary> {
+		self.tables.iter()
+			.flat_map(|tables| tables.iter().enumerate())
+			.map(|(table_index, table)| TableSummary {
+				size_class: table.datum_size().into(),
+				table_index,
+				value_size: table.value_size(),
+				total: table.total(),
+				used: table.used(),
+				touched: table.touched(),
+				bytes_used: table.bytes_used(),
+			})
+			.collect()
+	}
+
+	/// How the items use their space, as for `Database::stats`.
+	pub fn stats(&self) -> Stats {
+		let scheme = &self.metadata.size_scheme;
+		let classes = self.tables.iter()
+			.filter(|tables| !tables.is_empty())
+			.map(|tables| {
+				let datum_size = tables[0].datum_size();
+				let mut stats = ClassStats {
+					datum_size,
+					value_size: scheme.size(datum_size),
+					tables: tables.len(),
+					items: 0,
+					value_bytes: 0,
+					padding_bytes: 0,
+					header_bytes: 0,
+					value_sizes: vec![],
+				};
+				let mut sizes = BTreeMap::<u32, u32>::new();
+				for table in tables {
+					for len in table.allocated().into_iter().filter_map(|i| table.item_len(i)) {
+						stats.items += 1;
+						stats.value_bytes += len as u64;
+						stats.padding_bytes += table.value_size().saturating_sub(len) as u64;
+						stats.header_bytes += table.item_header_size() as u64;
+						*sizes.entry(len as u32).or_default() += 1;
+					}
+				}
+				stats.value_sizes = sizes.into_iter().collect();
+				stats
+			})
+			.collect();
+		Stats { classes, index_entry_bytes: self.index.entry_size() }
+	}
+
+	/// Check the content tables for consistency, as for `Database::verify`.
+	pub fn verify(&self) -> Vec<String> {
+		self.tables.iter().flatten().flat_map(|table| table.check()).collect()
+	}
+
+	/// The health of the index, as for `Database::index_stats`. Since nothing is inserted through
+	/// an inspector, the watermarks are always zero.
+	pub fn index_stats(&self) -> IndexStats {
+		let tunables = &self.metadata.tunables;
+		IndexStats {
+			triggers: (tunables.skipped_count_trigger, tunables.key_correction_trigger as usize),
+			..self.index.stats()
+		}
+	}
+
+	/// Call `f` for each entry in the index, in index order, with its position, the part of its
+	/// key which the index holds, how many positions past the ideal one it is and the address of
+	/// its item.
+	pub fn for_each_index_entry(&self, mut f: impl FnMut(usize, &[u8], usize, &ContentAddress)) {
+		let scheme = &self.metadata.size_scheme;
+		self.index.for_each_entry(|i, partial_key, correction, address|
+			f(i, partial_key, correction, &address.expand(scheme))
+		);
+	}
+
+	/// The item at `address`, if there is one.
+	pub fn item(&self, address: &ContentAddress) -> Option<RawItem> {
+		self.tables.get(u8::from(address.datum_size) as usize)?
+			.get(address.content_table)?
+			.item(address.entry_index as TableItemIndex)
+	}
+
+	/// Call `f` for each item in the content tables, in the order in which they're stored, with its
+	/// address.
+	pub fn for_each_item(&self, mut f: impl FnMut(&ContentAddress, &RawItem)) {
+		for tables in &self.tables {
+			for (content_table, table) in tables.iter().enumerate() {
+				for i in table.allocated() {
+					if let Some(item) = table.item(i) {
+						let address = ContentAddress { datum_size: table.datum_size(), content_table, entry_index: i as usize };
+						f(&address, &item);
+					}
+				}
+			}
+		}
+	}
+
+	/// The key of every item, along with its address and the key's place in the order in which
+	/// `Database::diff` walks items.
+	pub(crate) fn in_key_order(&self) -> impl Iterator<Item = (u64, Vec<u8>, ContentAddress)> + '_ {
+		let scheme = &self.metadata.size_scheme;
+		self.index.in_key_order(move |address| self.item_key(&address.expand(scheme))
+			.expect("Item is indexed. Database corrupt?")
+		).map(move |(place, key, address)| (place, key, address.expand(scheme)))
+	}
+
+	/// The key of the item at `address`, if there is one.
+	fn item_key(&self, address: &ContentAddress) -> Option<Vec<u8>> {
+		self.tables.get(u8::from(address.datum_size) as usize)?
+			.get(address.content_table)?
+			.item_key(address.entry_index as TableItemIndex)
+	}
+
+	/// How many items there are of each value size, in order of size. See `Database::value_sizes`.
+	pub fn value_sizes(&self) -> Vec<(u32, u32)> {
+		let mut sizes = BTreeMap::<u32, u32>::new();
+		self.for_each_item(|_, item| *sizes.entry(item.value.len() as u32).or_default() += 1);
+		sizes.into_iter().collect()
+	}
+
+	/// Write every item to `output` as an export stream, returning the number of items written.
+	/// See `Database::export`.
+	pub fn export(&self, output: impl Write) -> Result<u64, Error> {
+		let mut writer = ExportWriter::new(output, self.key_length)?;
+		let mut result = Ok(());
+		self.for_each_item(|_, item| if result.is_ok() {
+			result = writer.write(&item.key, item.ref_count, &item.value);
+		});
+		result?;
+		writer.finish()
+	}
+
+	/// The item with `key`, if there is one.
+	pub fn get(&self, key: &[u8]) -> Option<RawItem> {
+		if key.len() != self.key_length {
+			return None
+		}
+		let scheme = &self.metadata.size_scheme;
+		self.index.with_item_try(&key.to_vec(), |entry| match self.item(&entry.address.expand(scheme)) {
+			Some(item) if item.key == key => Ok(item),
+			_ => Err(()),
+		})
+	}
+}
@@ -0,0 +1,205 @@
+use std::hash::Hasher;
+use parity_scale_codec::{self as codec, Encode, Decode};
+use twox_hash::XxHash64;
+use crate::content::Content;
+use crate::content_address::ContentAddress;
+use crate::datum_size::{DatumSize, SizeScheme};
+use crate::table::{RefCount, TableItemIndex};
+use crate::types::{KeyType, TableIndex};
+use crate::Error;
+
+/// A position in the content tables from which an iteration may be resumed, even by another
+/// process: everything stored before it has been visited. It may be kept by encoding it.
+///
+/// Items stored after the cursor was taken are visited only if they land after the cursor, and
+/// those removed are skipped if the cursor hasn't reached them. No key is ever visited twice: an
+/// item which the cursor has passed might be stored again after it once removed, or be moved
+/// after it by compacting, so once any table which the cursor has passed has had an item freed
+/// or moved into it, resuming fails with `StaleCursor`. So does resuming a cursor taken before the
+/// database was repacked into another size scheme.
+///
+/// The iteration of a single size class resumes within that size class.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cursor {
+	/// The size class being walked.
+	pub datum_size: DatumSize,
+	/// The content table of that size class being walked.
+	pub content_table: TableIndex,
+	/// The slot of that table at which to continue.
+	pub entry_index: TableItemIndex,
+	/// The last size class to walk.
+	pub last: DatumSize,
+	/// The size class from which the walk began.
+	first: DatumSize,
+	/// Identifies the size scheme for which the position is meaningful.
+	scheme: u64,
+	/// Identifies the changes made to the tables which the walk has passed.
+	walked: u64,
+}
+
+impl Encode for Cursor {
+	fn encode_to<O: codec::Output>(&self, dest: &mut O) {
+		u8::from(self.datum_size).encode_to(dest);
+		(self.content_table as u32).encode_to(dest);
+		self.entry_index.encode_to(dest);
+		u8::from(self.last).encode_to(dest);
+		u8::from(self.first).encode_to(dest);
+		self.scheme.encode_to(dest);
+		self.walked.encode_to(dest);
+	}
+}
+
+impl Decode for Cursor {
+	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
+		Ok(Self {
+			datum_size: DatumSize::from(u8::decode(input)?),
+			content_table: u32::decode(input)? as TableIndex,
+			entry_index: Decode::decode(input)?,
+			last: DatumSize::from(u8::decode(input)?),
+			first: DatumSize::from(u8::decode(input)?),
+			scheme: Decode::decode(input)?,
+			walked: Decode::decode(input)?,
+		})
+	}
+}
+
+/// A fingerprint of `scheme`, so that a cursor can't be used with another.
+fn fingerprint(scheme: &SizeScheme) -> u64 {
+	let mut hasher = XxHash64::with_seed(0);
+	scheme.using_encoded(|e| hasher.write(e));
+	hasher.finish()
+}
+
+/// A fingerprint of the tables which a walk from size class `first` has passed on its way to
+/// `next`, the table of `next` included: those which have had items freed or moved into them and
+/// how many times. Tables which have had neither are left out, so that new tables make no
+/// difference.
+fn walked<K: KeyType>(content: &Content<K>, first: DatumSize, next: &ContentAddress) -> u64 {
+	let mut hasher = XxHash64::with_seed(0);
+	for s in u8::from(first)..=u8::from(next.datum_size) {
+		let changes = content.table_changes(DatumSize::from(s));
+		let passed = if s == u8::from(next.datum_size) {
+			(next.content_table + 1).min(changes.len())
+		} else {
+			changes.len()
+		};
+		for (content_table, &c) in changes[..passed].iter().enumerate().filter(|&(_, &c)| c > 0) {
+			(s, content_table as u32, c).using_encoded(|e| hasher.write(e));
+		}
+	}
+	hasher.finish()
+}
+
+/// A walk over the items of some size classes of the content tables, in the order in which they
+/// are stored.
+struct Walk<'a, K: KeyType> {
+	content: &'a Content<K>,
+	/// Where to look for the next item.
+	next: ContentAddress,
+	/// The first size class to walk.
+	first: DatumSize,
+	/// The last size class to walk.
+	last: DatumSize,
+}
+
+impl<'a, K: KeyType> Walk<'a, K> {
+	fn new(content: &'a Content<K>, first: DatumSize, last: DatumSize) -> Self {
+		Self { content, next: ContentAddress { datum_size: first, content_table: 0, entry_index: 0 }, first, last }
+	}
+
+	fn resume(content: &'a Content<K>, cursor: &Cursor) -> Result<Self, Error> {
+		if cursor.scheme != fingerprint(content.scheme()) {
+			return Err(Error::StaleCursor)
+		}
+		let next = ContentAddress {
+			datum_size: cursor.datum_size,
+			content_table: cursor.content_table,
+			entry_index: cursor.entry_index as usize,
+		};
+		if cursor.walked != walked(content, cursor.first, &next) {
+			return Err(Error::StaleCursor)
+		}
+		Ok(Self { content, next, first: cursor.first, last: cursor.last })
+	}
+
+	fn cursor(&self) -> Cursor {
+		Cursor {
+			datum_size: self.next.datum_size,
+			content_table: self.next.content_table,
+			entry_index: self.next.entry_index as TableItemIndex,
+			last: self.last,
+			first: self.first,
+			scheme: fingerprint(self.content.scheme()),
+			walked: walked(self.content, self.first, &self.next),
+		}
+	}
+
+	/// Move on to the next item, returning its address and key.
+	fn step(&mut self) -> Option<(ContentAddress, K)> {
+		let address = self.content.next_item(&self.next, self.last)?;
+		self.next = ContentAddress { entry_index: address.entry_index + 1, ..address.clone() };
+		let compact = address.compact(self.content.scheme()).expect("Item is allocated. Database corrupt?");
+		let key = self.content.item_hash(&compact).expect("Item is allocated. Database corrupt?");
+		Some((address, key))
+	}
+}
+
+/// An iterator over the key, reference count and value of each item in a database, in the order in
+/// which they are stored. See `Database::iter`.
+pub struct Iter<'a, K: KeyType>(Walk<'a, K>);
+
+impl<'a, K: KeyType> Iter<'a, K> {
+	pub(crate) fn new(content: &'a Content<K>, first: DatumSize, last: DatumSize) -> Self {
+		Self(Walk::new(content, first, last))
+	}
+
+	pub(crate) fn resume(content: &'a Content<K>, cursor: &Cursor) -> Result<Self, Error> {
+		Walk::resume(content, cursor).map(Self)
+	}
+
+	/// Where the iteration has got to, so that it may be resumed with `Database::iter_from`.
+	pub fn cursor(&self) -> Cursor {
+		self.0.cursor()
+	}
+}
+
+impl<'a, K: KeyType> Iterator for Iter<'a, K> {
+	type Item = (K, RefCount, Vec<u8>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let (address, key) = self.0.step()?;
+		let content = self.0.content;
+		let address = address.compact(content.scheme()).expect("Item is allocated. Database corrupt?");
+		let ref_count = content.item_ref_count(&address, None).expect("Item is allocated. Database corrupt?");
+		let value = content.item_ref(&address, None).expect("Item is allocated. Database corrupt?").to_vec();
+		content.release_mapping(&address);
+		Some((key, ref_count, value))
+	}
+}
+
+/// An iterator over the key of each item in a database, in the order in which they are stored. See
+/// `Database::keys`.
+pub struct Keys<'a, K: KeyType>(Walk<'a, K>);
+
+impl<'a, K: KeyType> Keys<'a, K> {
+	pub(crate) fn new(content: &'a Content<K>, first: DatumSize, last: DatumSize) -> Self {
+		Self(Walk::new(content, first, last))
+	}
+
+	pub(crate) fn resume(content: &'a Content<K>, cursor: &Cursor) -> Result<Self, Error> {
+		Walk::resume(content, cursor).map(Self)
+	}
+
+	/// Where the iteration has got to, so that it may be resumed with `Database::keys_from`.
+	pub fn cursor(&self) -> Cursor {
+		self.0.cursor()
+	}
+}
+
+impl<'a, K: KeyType> Iterator for Keys<'a, K> {
+	type Item = K;
+
+	fn next(&mut self) -> Option<K> {
+		self.0.step().map(|(_, key)| key)
+	}
+}
@@ -3,17 +3,28 @@ mod content_address;
 mod datum_size;
 mod database;
 mod error;
+mod export;
+mod freemap;
 mod index;
 mod index_item;
+mod inspector;
+mod iter;
 mod metadata;
 mod safe_database;
+mod stats;
 mod table;
 mod types;
 
-pub use database::{Options, Database};
+pub use database::{Options, Database, Difference, MergeProgress};
 pub use safe_database::SafeDatabase;
 pub use content_address::ContentAddress;
+pub use datum_size::{DatumSize, SizeScheme};
 pub use error::Error;
+pub use export::{ExportReader, ExportRecord, ExportWriter};
+pub use inspector::{Inspector, TableSummary};
+pub use iter::{Cursor, Iter, Keys};
+pub use stats::{ClassStats, IndexStats, Stats};
+pub use table::RawItem;
 pub use types::KeyType;
 
 // DONE: Better format for index n-bytes up to 4 bytes rest-of-key, 16-bit location-correction, 8-
@@ -42,6 +53,7 @@ mod tests {
 	use log::info;
 	use std::path::PathBuf;
 	use crate::types::Blake2Output;
+	use crate::datum_size::DatumSize;
 
 	fn init() {
 		let _ = simplelog::CombinedLogger::init(
@@ -145,6 +157,1017 @@ mod tests {
 		assert_eq!(db.bytes_mapped(), 3 * 1024 * 1024 + 655360);
 	}
 
+	#[test]
+	fn separate_storage_paths_work() {
+		init();
+		let path = PathBuf::from("/tmp/test-separate_storage_paths_work");
+		let _ = std::fs::remove_dir_all(&path);
+		let index_path = path.join("fast");
+		let content_path = path.join("content");
+		let oversize_path = path.join("bulk");
+
+		type Key = Blake2Output<[u8; 8]>;
+		let (small, big) = {
+			let mut db = Options::new()
+				.key_bytes(2)
+				.index_bits(4)
+				.path(path.join("main"))
+				.index_path(index_path.clone())
+				.content_path(content_path.clone())
+				.oversize_path(oversize_path.clone())
+				.open::<Key>()
+				.unwrap();
+			(db.store(b"Hello world!").1, db.store(&[0u8; 1024 * 1024][..]).1)
+		};
+
+		assert!(path.join("main/metadata.subdb").is_file());
+		assert!(index_path.join("index.subdb").is_file());
+		assert!(content_path.join("0-0.content").is_file());
+		assert!(oversize_path.join("63-0.content").is_file());
+		assert!(oversize_path.join("63-0.0").is_file());
+
+		{
+			// The locations should be found from the metadata.
+			let db = Options::from_path(path.join("main")).open::<Key>().unwrap();
+			assert_eq!(db.get(&small).unwrap(), b"Hello world!");
+			assert_eq!(db.get_ref(&big).unwrap().as_ref(), &[0u8; 1024 * 1024][..]);
+		}
+	}
+
+	#[test]
+	fn relative_storage_paths_follow_the_database() {
+		init();
+		let path = PathBuf::from("/tmp/test-relative_storage_paths_follow_the_database");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let key = {
+			let mut db = Options::from_path(path.join("main"))
+				.index_path(PathBuf::from("../fast"))
+				.open::<Key>()
+				.unwrap();
+			db.store(b"Hello world!").1
+		};
+		assert!(path.join("fast/index.subdb").is_file());
+
+		// Moving everything together keeps the database whole.
+		let moved = PathBuf::from("/tmp/test-relative_storage_paths_follow_the_database-moved");
+		let _ = std::fs::remove_dir_all(&moved);
+		std::fs::rename(&path, &moved).unwrap();
+		let db = Options::from_path(moved.join("main")).open::<Key>().unwrap();
+		assert_eq!(db.get(&key).unwrap(), b"Hello world!");
+	}
+
+	#[test]
+	fn size_scheme_is_persisted() {
+		init();
+		let path = PathBuf::from("/tmp/test-size_scheme_is_persisted");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let scheme = SizeScheme::new(vec![16, 65, 69]).unwrap();
+		let values = [vec![1u8; 10], vec![2u8; 65], vec![3u8; 69], vec![4u8; 70]];
+		let keys = {
+			let mut db = Options::from_path(path.clone())
+				.size_scheme(scheme.clone())
+				.open::<Key>()
+				.unwrap();
+			values.iter().map(|v| db.store(v).1).collect::<Vec<_>>()
+		};
+
+		// The scheme should be found from the metadata, whatever we ask for.
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.size_scheme(), &scheme);
+		let classes = db.info().iter().map(|x| u8::from((x.0).0)).collect::<Vec<_>>();
+		assert_eq!(classes, vec![0, 1, 2, 63]);
+		for (k, v) in keys.iter().zip(values.iter()) {
+			assert_eq!(&db.get(k).unwrap(), v);
+		}
+		assert_eq!(db.value_sizes(), vec![(10, 1), (65, 1), (69, 1), (70, 1)]);
+	}
+
+	#[test]
+	fn small_values_take_less_space() {
+		init();
+		let path = PathBuf::from("/tmp/test-small_values_take_less_space");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let bytes_used = |name: &str, scheme: SizeScheme| {
+			let mut db = Options::from_path(path.join(name)).size_scheme(scheme).open::<Key>().unwrap();
+			for i in 0..4096u32 {
+				db.store(&i.to_le_bytes()[..]);
+			}
+			db.commit();
+			db.info().iter().map(|x| (x.1).2).sum::<usize>()
+		};
+		let default = bytes_used("default", SizeScheme::default());
+		let small = bytes_used("small", SizeScheme::small_values());
+		assert!(small * 2 < default, "{} vs {}", small, default);
+	}
+
+	#[test]
+	fn table_geometry_is_persisted() {
+		init();
+		let path = PathBuf::from("/tmp/test-table_geometry_is_persisted");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		// Tables of more than 65536 items for the small class, and of 256 for the large one.
+		let scheme = SizeScheme::new(vec![8, 4096]).unwrap().with_geometry(1024 * 1024, 1 << 17).unwrap();
+		let keys = {
+			let mut db = Options::from_path(path.clone())
+				.size_scheme(scheme.clone())
+				.open::<Key>()
+				.unwrap();
+			(0..70_000u32).map(|i| db.store(&i.to_le_bytes()[..]).1).collect::<Vec<_>>()
+		};
+		let big_keys = {
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			(0..=255u8).chain(0..10).map(|i| db.store(&[i; 4000][..]).1).collect::<Vec<_>>()
+		};
+
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.size_scheme(), &scheme);
+		let tables = db.info().iter().map(|x| ((x.0).0, (x.1).1)).collect::<Vec<_>>();
+		assert_eq!(tables, vec![
+			(DatumSize::Size(0), 70_000),
+			(DatumSize::Size(1), 256),
+		]);
+		for (i, k) in keys.iter().enumerate() {
+			assert_eq!(db.get(k).unwrap(), (i as u32).to_le_bytes());
+		}
+		let addresses = db.info().iter().map(|x| ((x.0).0, x.2)).collect::<Vec<_>>();
+		assert_eq!(addresses, vec![
+			(DatumSize::Size(0), ((1 << 26) - (1 << 17), 1 << 26)),
+			(DatumSize::Size(1), ((1 << 26) - 256, 1 << 26)),
+		]);
+		for (i, k) in big_keys.iter().enumerate() {
+			assert_eq!(db.get(k).unwrap(), vec![i as u8; 4000]);
+			assert_eq!(db.get_ref_count(k), if i % 256 < 10 { 2 } else { 1 });
+		}
+	}
+
+	#[test]
+	fn small_values_cost_at_most_double_with_their_keys() {
+		init();
+		let path = PathBuf::from("/tmp/test-small_values_cost_at_most_double_with_their_keys");
+		let _ = std::fs::remove_dir_all(&path);
+
+		// Each item must keep its whole key, so the cost of an item is measured against the size of
+		// its key and value together. The worst case for each class is its smallest value.
+		type Key = Blake2Output<[u8; 32]>;
+		let worst_ratio = |name: &str, scheme: SizeScheme| {
+			let mut db = Options::from_path(path.join(name)).size_scheme(scheme).open::<Key>().unwrap();
+			for i in 0..1024u32 {
+				let mut value = i.to_le_bytes().to_vec();
+				value.resize(i as usize % 24 + 1, 0xff);
+				db.store(&value);
+			}
+			let stats = db.stats();
+			stats.classes.iter()
+				.map(|c| {
+					let cost = (c.value_bytes + c.padding_bytes + c.header_bytes) as f64 / c.items as f64
+						+ stats.index_entry_bytes as f64;
+					cost / (32 + c.value_sizes[0].0) as f64
+				})
+				.fold(0.0, f64::max)
+		};
+		let small = worst_ratio("small", SizeScheme::small_values());
+		assert!(small <= 2.0, "{}", small);
+		let default = worst_ratio("default", SizeScheme::default());
+		assert!(default > 2.0, "{}", default);
+	}
+
+	#[test]
+	fn repack_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-repack_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let values = (0..200u32).map(|i| vec![i as u8; 10 + i as usize * 3]).collect::<Vec<_>>();
+		let big = vec![42u8; 200 * 1024];
+		let scheme = SizeScheme::new(vec![64, 256, 1024]).unwrap();
+		let (keys, big_key) = {
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			let keys = values.iter().map(|v| db.store(v).1).collect::<Vec<_>>();
+			db.store(&values[7]);
+			let big_key = db.store(&big).1;
+			db.repack(scheme.clone()).unwrap();
+			assert_eq!(db.size_scheme(), &scheme);
+			(keys, big_key)
+		};
+
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.size_scheme(), &scheme);
+		for (i, (k, v)) in keys.iter().zip(values.iter()).enumerate() {
+			assert_eq!(&db.get(k).unwrap(), v);
+			assert_eq!(db.get_ref_count(k), if i == 7 { 2 } else { 1 });
+		}
+		assert_eq!(db.get(&big_key).unwrap(), big);
+		let classes = db.info().iter().map(|x| u8::from((x.0).0)).collect::<Vec<_>>();
+		assert_eq!(classes, vec![0, 1, 2, 63]);
+		assert!(db.verify().is_empty());
+		assert!(!path.join("repack").exists());
+	}
+
+	#[test]
+	fn interrupted_repack_resumes_on_open() {
+		use crate::metadata::{Metadata, MetadataV2, Repack, RepackStage};
+		init();
+		let path = PathBuf::from("/tmp/test-interrupted_repack_resumes_on_open");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let scheme = SizeScheme::new(vec![16, 48]).unwrap();
+		let keys = {
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			(0..40u8).map(|i| db.store(&[i; 40][..]).1).collect::<Vec<_>>()
+		};
+		let mut metadata = MetadataV2::try_read(&path).unwrap().unwrap();
+		metadata.repack = Some(Repack { scheme: scheme.clone(), stage: RepackStage::Moving });
+		metadata.write(&path).unwrap();
+
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.size_scheme(), &scheme);
+		for (i, k) in keys.iter().enumerate() {
+			assert_eq!(db.get(k).unwrap(), vec![i as u8; 40]);
+		}
+		assert!(MetadataV2::try_read(&path).unwrap().unwrap().repack.is_none());
+	}
+
+	#[test]
+	fn incompatible_keys_are_refused() {
+		init();
+		let path = PathBuf::from("/tmp/test-incompatible_keys_are_refused");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let bad_options = |e: Option<Error>| matches!(e, Some(Error::BadOptions(_)));
+		assert!(bad_options(Options::from_path(path.clone()).key_bytes(9).open::<Key>().err()));
+		assert!(bad_options(Options::from_path(path.clone()).index_bits(40).open::<Blake2Output<[u8; 4]>>().err()));
+		assert!(bad_options(Options::from_path(path.clone()).index_bits(65).open::<Key>().err()));
+
+		let key = {
+			let mut db = Options::from_path(path.clone()).hasher("blake2b-64").open::<Key>().unwrap();
+			db.store(b"Hello world!").1
+		};
+
+		let incompatible = |e: Option<Error>| matches!(e, Some(Error::IncompatibleKey(_)));
+		assert!(incompatible(Options::from_path(path.clone()).open::<Blake2Output<[u8; 16]>>().err()));
+		assert!(incompatible(Options::from_path(path.clone()).hasher("twox-64").open::<Key>().err()));
+
+		let db = Options::from_path(path.clone()).hasher("blake2b-64").open::<Key>().unwrap();
+		assert_eq!(db.get(&key), Some(b"Hello world!".to_vec()));
+	}
+
+	#[test]
+	fn tunables_are_persisted() {
+		use crate::metadata::{Metadata, MetadataV2};
+		init();
+		let path = PathBuf::from("/tmp/test-tunables_are_persisted");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		Options::from_path(path.clone())
+			.min_items_backed(1)
+			.oversize_shrink(1 << 20, 1 << 18)
+			.open::<Key>()
+			.unwrap();
+		let tunables = || MetadataV2::try_read(&path).unwrap().unwrap().tunables;
+		Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(tunables().min_items_backed, 1);
+		assert_eq!(tunables().oversize_trigger_mapped, 1 << 20);
+		assert_eq!(tunables().oversize_shrink_mapped, 1 << 18);
+		assert_eq!(tunables().skipped_count_trigger, 240);
+
+		Options::from_path(path.clone()).reindex_triggers(100, 16).open::<Key>().unwrap();
+		assert_eq!(tunables().skipped_count_trigger, 100);
+		assert_eq!(tunables().key_correction_trigger, 16);
+		assert_eq!(tunables().min_items_backed, 1);
+		assert!(!path.join("metadata.subdb.tmp").exists());
+	}
+
+	#[test]
+	fn old_metadata_is_upgraded() {
+		use parity_scale_codec::Encode;
+		use crate::metadata::{Metadata, MetadataV2};
+		init();
+		let path = PathBuf::from("/tmp/test-old_metadata_is_upgraded");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let key = {
+			let mut db = Options::from_path(path.clone()).key_bytes(3).index_bits(12).open::<Key>().unwrap();
+			db.store(b"Hello world!").1
+		};
+		// The first version of the metadata held just the index geometry.
+		std::fs::write(path.join("metadata.subdb"), (b"SBDB", 1u32, 3u32, 12u32).encode()).unwrap();
+
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.get(&key), Some(b"Hello world!".to_vec()));
+		let metadata = std::fs::read(path.join("metadata.subdb")).unwrap();
+		assert_eq!(&metadata[..8], &(b"SBDB", 2u32).encode()[..]);
+		let metadata = MetadataV2::try_read(&path).unwrap().unwrap();
+		assert_eq!((metadata.key_bytes, metadata.index_bits), (3, 12));
+		assert_eq!(metadata.key_length, Some(8));
+		assert_eq!(metadata.size_scheme, SizeScheme::default());
+
+		std::fs::write(path.join("metadata.subdb"), (b"SBDB", 3u32).encode()).unwrap();
+		assert!(matches!(Options::from_path(path.clone()).open::<Key>().err(), Some(Error::UnsupportedVersion)));
+	}
+
+	#[test]
+	fn unknown_features_are_refused_before_writing() {
+		use crate::metadata::{Metadata, MetadataV2};
+		init();
+		let path = PathBuf::from("/tmp/test-unknown_features_are_refused_before_writing");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		drop(Options::from_path(path.clone()).open::<Key>().unwrap());
+		let mut metadata = MetadataV2::try_read(&path).unwrap().unwrap();
+		metadata.features = 1 << 63;
+		metadata.write(&path).unwrap();
+		let before = std::fs::read(path.join("metadata.subdb")).unwrap();
+
+		let opened = Options::from_path(path.clone())
+			.content_path(path.join("content"))
+			.hasher("blake2b-64")
+			.open::<Key>();
+		assert!(matches!(opened.err(), Some(Error::UnsupportedVersion)));
+		assert_eq!(std::fs::read(path.join("metadata.subdb")).unwrap(), before);
+		assert!(matches!(Inspector::open(&path).err(), Some(Error::UnsupportedVersion)));
+	}
+
+	#[test]
+	fn inspector_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-inspector_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; if i < 5 { 200_000 } else { 40 + i }];
+		let keys = {
+			let mut db = Options::from_path(path.clone()).hasher("blake2b-64").open::<Key>().unwrap();
+			let keys = (0..50).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+			db.store(&value(1));
+			keys
+		};
+		let metadata = std::fs::read(path.join("metadata.subdb")).unwrap();
+
+		let inspector = Inspector::open(&path).unwrap();
+		assert_eq!(inspector.key_length(), 8);
+		assert_eq!(inspector.hasher(), Some("blake2b-64"));
+		assert_eq!(inspector.size_scheme(), &SizeScheme::default());
+		for (i, key) in keys.iter().enumerate() {
+			let item = inspector.get(key.as_ref()).unwrap();
+			assert_eq!(item.value, value(i));
+			assert_eq!(item.ref_count, if i == 1 { 2 } else { 1 });
+		}
+		assert!(inspector.get(&[0u8; 8]).is_none());
+		assert!(inspector.get(&[0u8; 4]).is_none());
+
+		let mut items = 0;
+		inspector.for_each_item(|address, item| {
+			assert_eq!(inspector.item(address).as_ref(), Some(item));
+			items += 1;
+		});
+		assert_eq!(items, 50);
+		let mut entries = 0;
+		inspector.for_each_index_entry(|_, partial_key, _, address| {
+			let item = inspector.item(address).unwrap();
+			assert!(item.key.starts_with(partial_key));
+			entries += 1;
+		});
+		assert_eq!(entries, 50);
+		assert_eq!(inspector.tables().iter().map(|t| t.used as usize).sum::<usize>(), 50);
+		assert_eq!(inspector.tables().iter().filter(|t| t.size_class == 63).count(), 1);
+		let sizes = inspector.value_sizes();
+		assert_eq!(sizes.len(), 46);
+		assert_eq!(sizes[0], (45, 1));
+		assert_eq!(sizes[45], (200_000, 5));
+		assert!(inspector.verify().is_empty());
+		let stats = inspector.stats();
+		drop(inspector);
+
+		assert_eq!(std::fs::read(path.join("metadata.subdb")).unwrap(), metadata);
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.stats(), stats);
+	}
+
+	#[test]
+	fn keys_may_be_longer_than_arrays_allow() {
+		init();
+		let path = PathBuf::from("/tmp/test-keys_may_be_longer_than_arrays_allow");
+		let _ = std::fs::remove_dir_all(&path);
+
+		#[derive(Clone, PartialEq, Eq, Debug)]
+		struct Key(Vec<u8>);
+		impl Default for Key {
+			fn default() -> Self { Key(vec![0; 64]) }
+		}
+		impl AsRef<[u8]> for Key {
+			fn as_ref(&self) -> &[u8] { &self.0 }
+		}
+		impl AsMut<[u8]> for Key {
+			fn as_mut(&mut self) -> &mut [u8] { &mut self.0 }
+		}
+
+		let key = Key((0..64).collect());
+		{
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			db.insert(b"Hello world!", &key);
+		}
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.get(&key), Some(b"Hello world!".to_vec()));
+		drop(db);
+
+		let inspector = Inspector::open(&path).unwrap();
+		assert_eq!(inspector.key_length(), 64);
+		assert_eq!(inspector.get(&key.0).unwrap().value, b"Hello world!".to_vec());
+	}
+
+	#[test]
+	fn export_and_import_work() {
+		init();
+		let path = PathBuf::from("/tmp/test-export_and_import_work");
+		let other_path = PathBuf::from("/tmp/test-export_and_import_work-other");
+		let _ = std::fs::remove_dir_all(&path);
+		let _ = std::fs::remove_dir_all(&other_path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; if i < 3 { 200_000 } else { i * 7 }];
+		let mut stream = vec![];
+		let keys = {
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			let keys = (0..100).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+			db.store(&value(1));
+			db.store(&value(50));
+			db.store(&value(50));
+			assert_eq!(db.export(&mut stream).unwrap(), 100);
+			keys
+		};
+		let mut inspected = vec![];
+		Inspector::open(&path).unwrap().export(&mut inspected).unwrap();
+		assert_eq!(inspected, stream);
+
+		let mut db = Options::new()
+			.key_bytes(4)
+			.index_bits(12)
+			.size_scheme(SizeScheme::small_values())
+			.path(other_path.clone())
+			.open::<Key>()
+			.unwrap();
+		db.store(&value(50));
+		assert_eq!(db.import(&stream[..]).unwrap(), 100);
+		let check = |db: &Database<Key>| for (i, key) in keys.iter().enumerate() {
+			assert_eq!(db.get(key), Some(value(i)));
+			assert_eq!(db.get_ref_count(key), match i { 1 => 2, 50 => 4, _ => 1 });
+		};
+		check(&db);
+		assert!(db.verify().is_empty());
+
+		// A corrupt stream is found to be so before any of it is imported.
+		let stats = db.stats();
+		let mut corrupt = stream.clone();
+		let last = corrupt.len() - 1;
+		corrupt[last] ^= 1;
+		assert!(matches!(db.import(&corrupt[..]), Err(Error::BadExport(_))));
+		check(&db);
+		assert_eq!(db.stats(), stats);
+		assert!(!other_path.join("import.subdb.tmp").exists());
+		let mut wrong_key = Options::from_path(other_path.join("wrong")).open::<[u8; 4]>().unwrap();
+		assert!(matches!(wrong_key.import(&stream[..]), Err(Error::IncompatibleKey(_))));
+	}
+
+	#[test]
+	fn merge_works() {
+		use std::convert::TryInto;
+		use crate::metadata::MergeCheckpoint;
+		use crate::table::RawItem;
+		init();
+		let path = PathBuf::from("/tmp/test-merge_works");
+		let _ = std::fs::remove_dir_all(&path);
+		let source_path = path.join("source");
+		let target_path = path.join("target");
+		let resumed_path = path.join("resumed");
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: u32| i.to_le_bytes().to_vec();
+		let keys = {
+			let mut source = Options::from_path(source_path.clone()).open::<Key>().unwrap();
+			let keys = (0..5000).map(|i| source.store(&value(i)).1).collect::<Vec<_>>();
+			source.store(&value(7));
+			keys
+		};
+
+		let mut target = Options::new()
+			.index_bits(12)
+			.path(target_path.clone())
+			.open::<Key>()
+			.unwrap();
+		target.store(&value(7));
+		let extra = target.store(&value(6000)).1;
+		let mut reports = vec![];
+		assert_eq!(target.merge_from(&source_path, |p| reports.push(p)).unwrap(), 5000);
+		assert_eq!(reports, vec![
+			MergeProgress { merged: 4096, total: 5000 },
+			MergeProgress { merged: 5000, total: 5000 },
+		]);
+		for (i, key) in keys.iter().enumerate() {
+			assert_eq!(target.get(key), Some(value(i as u32)));
+			assert_eq!(target.get_ref_count(key), if i == 7 { 3 } else { 1 });
+		}
+		assert_eq!(target.get_ref_count(&extra), 1);
+		assert!(MergeCheckpoint::read(&target_path).unwrap().is_none());
+
+		// Pretend that a merge died part of the way through its second batch.
+		let mut order = vec![];
+		Inspector::open(&source_path).unwrap().for_each_item(|_, item| order.push(item.clone()));
+		let key_of = |item: &RawItem| Blake2Output(item.key.as_slice().try_into().unwrap());
+		let mut resumed = Options::from_path(resumed_path.clone()).open::<Key>().unwrap();
+		let already = &order[4100];
+		resumed.insert(&already.value, &key_of(already));
+		let checkpoint = MergeCheckpoint {
+			source: source_path.canonicalize().unwrap().to_string_lossy().into_owned(),
+			merged: 4096,
+			prior_ref_counts: order[4096..].iter().map(|item| (item.key.clone(), resumed.get_ref_count(&key_of(item)))).collect(),
+		};
+		checkpoint.write(&resumed_path).unwrap();
+		for item in &order[4096..4500] {
+			for _ in 0..item.ref_count {
+				resumed.insert(&item.value, &key_of(item));
+			}
+		}
+		assert_eq!(resumed.merge_from(&source_path, |_| ()).unwrap(), 5000);
+		assert_eq!(keys.iter().filter(|key| resumed.contains_key(key)).count(), 904);
+		for item in &order[4096..] {
+			let expected = item.ref_count + if item == already { 1 } else { 0 };
+			assert_eq!(resumed.get_ref_count(&key_of(item)), expected);
+		}
+		assert!(MergeCheckpoint::read(&resumed_path).unwrap().is_none());
+	}
+
+	#[test]
+	fn diff_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-diff_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: u32| i.to_le_bytes().to_vec();
+		let mut a = Options::from_path(path.join("a")).open::<Key>().unwrap();
+		let mut b = Options::new()
+			.index_bits(10)
+			.size_scheme(SizeScheme::small_values())
+			.path(path.join("b"))
+			.open::<Key>()
+			.unwrap();
+		for i in 0..500 {
+			a.store(&value(i));
+			b.store(&value(i));
+		}
+		let inspect = |name: &str| Inspector::open(&path.join(name)).unwrap();
+		assert_eq!(a.diff(&inspect("b"), |d| panic!("Unexpected difference {:?}", d)).unwrap(), 0);
+
+		let only_a = a.store(&value(1000)).1;
+		let only_b = b.store(&value(1001)).1;
+		let bumped = b.store(&value(3)).1;
+		let corrupt = Blake2Output([42u8; 8]);
+		a.insert(b"one thing", &corrupt);
+		b.insert(b"another", &corrupt);
+
+		let mut differences = vec![];
+		assert_eq!(a.diff(&inspect("b"), |d| differences.push(d)).unwrap(), 4);
+		differences.sort_by_key(|d| format!("{:?}", d));
+		let mut expected = vec![
+			Difference::OnlyInSelf(only_a),
+			Difference::OnlyInOther(only_b),
+			Difference::RefCountDiffers(bumped, 1, 2),
+			Difference::ValueDiffers(corrupt),
+		];
+		expected.sort_by_key(|d| format!("{:?}", d));
+		assert_eq!(differences, expected);
+
+		// Keys shorter than eight bytes, in indexes which are crowded and which differ in size.
+		let open = |name: &str, index_bits: usize| Options::new()
+			.key_bytes(4)
+			.index_bits(index_bits)
+			.path(path.join(name))
+			.open::<[u8; 4]>()
+			.unwrap();
+		let mut c = open("c", 12);
+		let mut d = open("d", 16);
+		for i in 0..3000u32 {
+			c.insert(&value(i), &i.to_le_bytes());
+			d.insert(&value(i), &i.to_le_bytes());
+		}
+		assert_eq!(c.diff(&inspect("d"), |d| panic!("Unexpected difference {:?}", d)).unwrap(), 0);
+		d.remove(&1234u32.to_le_bytes()).unwrap();
+		let mut differences = vec![];
+		assert_eq!(c.diff(&inspect("d"), |d| differences.push(d)).unwrap(), 1);
+		assert_eq!(differences, vec![Difference::OnlyInSelf(1234u32.to_le_bytes())]);
+		assert!(matches!(a.diff(&inspect("d"), |_| ()), Err(Error::IncompatibleKey(_))));
+
+		// Keys which collide in the smaller index, one of which is removed from the middle of its
+		// run, leaving a gap which the walk must probe past.
+		let mut e = open("e", 12);
+		let mut f = open("f", 16);
+		let keys = (0..200u32).map(|i| (i * 256).to_le_bytes()).collect::<Vec<_>>();
+		for (i, key) in keys.iter().enumerate() {
+			e.insert(&value(i as u32), key);
+			f.insert(&value(i as u32), key);
+		}
+		e.remove(&keys[17]).unwrap();
+		f.remove(&keys[17]).unwrap();
+		assert_eq!(e.diff(&inspect("f"), |d| panic!("Unexpected difference {:?}", d)).unwrap(), 0);
+		assert_eq!(f.diff(&inspect("e"), |d| panic!("Unexpected difference {:?}", d)).unwrap(), 0);
+	}
+
+	#[test]
+	fn iteration_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-iteration_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; if i < 3 { 200_000 } else { 1 + i * 3 }];
+		let mut db = Options::new()
+			.index_bits(8)
+			.path(path.clone())
+			.open::<Key>()
+			.unwrap();
+		assert_eq!(db.iter().count(), 0);
+		let mut expected = (0..1000)
+			.map(|i| (db.store(&value(i)).1, 1, value(i)))
+			.collect::<Vec<_>>();
+		// Storing 1000 items in 256 index entries will have reindexed a few times along the way.
+		db.store(&value(10));
+		expected[10].1 = 2;
+		for (key, _, _) in expected.iter().step_by(3) {
+			db.remove(key).unwrap();
+		}
+		let expected = expected.into_iter()
+			.enumerate()
+			.filter_map(|(i, item)| if i % 3 == 0 { None } else { Some(item) })
+			.collect::<Vec<_>>();
+
+		let mut items = db.iter().collect::<Vec<_>>();
+		assert_eq!(items.len(), expected.len());
+		let mut keys = db.keys().collect::<Vec<_>>();
+		assert_eq!(keys, items.iter().map(|(key, _, _)| key.clone()).collect::<Vec<_>>());
+		items.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+		let mut sorted = expected.clone();
+		sorted.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+		assert_eq!(items, sorted);
+
+		let mut by_class = db.iter_size_class(DatumSize::Oversize).map(|(key, _, _)| key).collect::<Vec<_>>();
+		assert_eq!(by_class, vec![expected[0].0.clone(), expected[1].0.clone()]);
+		for s in 0..63 {
+			by_class.extend(db.keys_size_class(DatumSize::from(s)));
+		}
+		by_class.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+		keys.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+		assert_eq!(by_class, keys);
+	}
+
+	#[test]
+	fn iteration_resumes() {
+		use parity_scale_codec::{Encode, Decode};
+		init();
+		let path = PathBuf::from("/tmp/test-iteration_resumes");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; 1 + i % 300];
+		let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		let keys = (0..1000).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+
+		let mut seen = vec![];
+		let cursor = {
+			let mut iter = db.keys();
+			seen.extend(iter.by_ref().take(400));
+			iter.cursor().encode()
+		};
+		// Carry on after a restart, with items having been stored since.
+		drop(db);
+		let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		let added = (1000..1200).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+		let cursor = Cursor::decode(&mut &cursor[..]).unwrap();
+		let mut iter = db.iter_from(&cursor).unwrap();
+		seen.extend(iter.by_ref().map(|(key, _, _)| key));
+		assert_eq!(db.keys_from(&iter.cursor()).unwrap().count(), 0);
+
+		let mut unique = seen.iter().map(|key| key.as_ref().to_vec()).collect::<Vec<_>>();
+		unique.sort();
+		unique.dedup();
+		assert_eq!(unique.len(), seen.len());
+		for (i, key) in keys.iter().enumerate() {
+			assert!(seen.contains(key), "Item {} missed", i);
+		}
+		assert!(seen.len() <= 1000 + added.len());
+
+		// An item which the cursor has passed might be stored again after it once removed, so the
+		// cursor may no longer be used.
+		let passed = seen[0].clone();
+		let passed_value = db.get(&passed).unwrap();
+		db.remove(&passed).unwrap();
+		db.store(&passed_value);
+		assert!(matches!(db.iter_from(&cursor).err(), Some(Error::StaleCursor)));
+
+		// Resuming the iteration of a size class stays within it.
+		let class = DatumSize::from(3);
+		let in_class = db.keys_size_class(class).collect::<Vec<_>>();
+		assert!(in_class.len() > 5);
+		let mut iter = db.keys_size_class(class);
+		let mut seen = iter.by_ref().take(5).collect::<Vec<_>>();
+		seen.extend(db.keys_from(&iter.cursor()).unwrap());
+		assert_eq!(seen, in_class);
+
+		db.repack(SizeScheme::small_values()).unwrap();
+		assert!(matches!(db.iter_from(&cursor).err(), Some(Error::StaleCursor)));
+	}
+
+	#[test]
+	fn parallel_scan_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-parallel_scan_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; if i < 3 { 200_000 } else { 1 + i * 5 }];
+		let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		for i in 0..2000 {
+			db.store(&value(i));
+		}
+		db.store(&value(7));
+		let mut expected = db.iter().map(|(key, rc, value)| (key.as_ref().to_vec(), rc, value)).collect::<Vec<_>>();
+		expected.sort();
+
+		for &threads in &[0, 1, 4, 1000] {
+			let seen = parking_lot::Mutex::new(vec![]);
+			db.par_for_each(threads, |key, rc, value| seen.lock().push((key.as_ref().to_vec(), rc, value.to_vec())));
+			let mut seen = seen.into_inner();
+			seen.sort();
+			assert_eq!(seen, expected);
+		}
+	}
+
+	#[test]
+	fn scans_unmap_oversize_items() {
+		init();
+		let path = PathBuf::from("/tmp/test-scans_unmap_oversize_items");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; if i < 20 { 200_000 } else { 1 + i }];
+		{
+			let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+			for i in 0..100 {
+				db.store(&value(i));
+			}
+		}
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		let unmapped = db.bytes_mapped();
+
+		assert_eq!(db.iter().count(), 100);
+		assert_eq!(db.bytes_mapped(), unmapped);
+		db.par_for_each(4, |_, _, _| ());
+		assert_eq!(db.bytes_mapped(), unmapped);
+		db.export(std::io::sink()).unwrap();
+		assert_eq!(db.bytes_mapped(), unmapped);
+		assert_eq!(db.diff(&Inspector::open(&path).unwrap(), |d| panic!("Unexpected difference {:?}", d)).unwrap(), 0);
+		assert_eq!(db.bytes_mapped(), unmapped);
+
+	}
+
+	#[test]
+	fn sampling_works() {
+		use rand::{SeedableRng, rngs::StdRng};
+		init();
+		let path = PathBuf::from("/tmp/test-sampling_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: usize| vec![i as u8; 1 + i];
+		let mut db = Options::new()
+			.index_bits(12)
+			.path(path.clone())
+			.open::<Key>()
+			.unwrap();
+		let mut rng = StdRng::seed_from_u64(42);
+		assert!(db.sample(10, &mut rng).is_empty());
+		let keys = (0..1000).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+
+		let mut hits = vec![0; keys.len()];
+		for _ in 0..200 {
+			let sample = db.sample(10, &mut rng);
+			assert_eq!(sample.len(), 10);
+			for (key, v) in &sample {
+				let i = keys.iter().position(|k| k == key).unwrap();
+				assert_eq!(v, &value(i));
+				hits[i] += 1;
+			}
+			let mut distinct = sample.iter().map(|(key, _)| key.as_ref().to_vec()).collect::<Vec<_>>();
+			distinct.sort();
+			distinct.dedup();
+			assert_eq!(distinct.len(), 10);
+		}
+		// Each item is expected to be picked twice, so few should be missed and none picked often.
+		assert!(hits.iter().filter(|&&h| h == 0).count() < 250);
+		assert!(hits.iter().all(|&h| h < 15));
+
+		assert_eq!(db.sample(1000, &mut rng).len(), 1000);
+		assert_eq!(db.sample(5000, &mut rng).len(), 1000);
+	}
+
+	#[test]
+	fn stats_work() {
+		init();
+		let path = PathBuf::from("/tmp/test-stats_work");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		assert_eq!(db.stats().items(), 0);
+		assert_eq!(db.stats().overhead_per_item(), 0.0);
+		for i in 0..10u8 {
+			db.store(&[i; 20]);
+			db.store(&[i; 32]);
+		}
+		db.store(&[1; 200_000]);
+
+		let stats = db.stats();
+		assert_eq!(stats.items(), 21);
+		assert_eq!(stats.value_bytes(), 10 * 20 + 10 * 32 + 200_000);
+		assert_eq!(stats.classes.len(), 2);
+		let small = &stats.classes[0];
+		assert_eq!(small.datum_size, DatumSize::from(0));
+		assert_eq!(small.value_size, Some(32));
+		assert_eq!(small.items, 20);
+		assert_eq!(small.value_bytes, 10 * 20 + 10 * 32);
+		assert_eq!(small.padding_bytes, 10 * 12);
+		assert_eq!(small.value_sizes, vec![(20, 10), (32, 10)]);
+		// A reference count, a byte of size correction and the key.
+		assert_eq!(small.header_bytes, 20 * (2 + 1 + 8));
+		let oversize = &stats.classes[1];
+		assert_eq!(oversize.datum_size, DatumSize::Oversize);
+		assert_eq!(oversize.value_size, None);
+		assert_eq!((oversize.items, oversize.padding_bytes), (1, 0));
+		let all = stats.classes.iter().flat_map(|c| c.value_sizes.iter().cloned()).collect::<Vec<_>>();
+		assert_eq!(all, db.value_sizes());
+		assert!(stats.overhead_per_item() > (stats.index_entry_bytes + 8) as f64);
+	}
+
+	#[test]
+	fn index_stats_work() {
+		init();
+		let path = PathBuf::from("/tmp/test-index_stats_work");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let mut db = Options::new()
+			.index_bits(10)
+			.path(path.clone())
+			.open::<Key>()
+			.unwrap();
+		let stats = db.index_stats();
+		assert_eq!((stats.slots, stats.occupied, stats.longest_cluster), (1024, 0, 0));
+		assert_eq!(stats.triggers, (240, 32));
+
+		for i in 0..600u32 {
+			db.store(&i.to_le_bytes());
+		}
+		let stats = db.index_stats();
+		assert_eq!((stats.index_bits, stats.slots, stats.occupied), (10, 1024, 600));
+		assert!((stats.load_factor() - 600.0 / 1024.0).abs() < 1e-9);
+		assert_eq!(stats.key_corrections.iter().map(|(_, n)| n).sum::<usize>(), 600);
+		let max_correction = stats.key_corrections.last().unwrap().0;
+		assert!(max_correction > 0);
+		assert!(stats.longest_cluster > max_correction);
+		assert_eq!(stats.max_skipped_count, stats.skipped_counts.last().unwrap().0);
+		// Nothing has been removed, so every correction was made by an insertion.
+		assert_eq!(stats.watermarks, (stats.max_skipped_count, max_correction));
+		assert_eq!(db.index_stats().watermarks, stats.watermarks);
+		drop(db);
+
+		let inspected = Inspector::open(&path).unwrap().index_stats();
+		assert_eq!(inspected, IndexStats { watermarks: (0, 0), ..stats });
+	}
+
+	#[test]
+	fn failed_reindex_resets_watermarks() {
+		init();
+		let path = PathBuf::from("/tmp/test-failed_reindex_resets_watermarks");
+		let _ = std::fs::remove_dir_all(&path);
+
+		// Keys of three bytes can't be indexed by more than 16 bits, so the reindex fails.
+		let mut db = Options::from_path(path.clone())
+			.key_bytes(2)
+			.index_bits(16)
+			.reindex_triggers(240, 1)
+			.open::<[u8; 3]>()
+			.unwrap();
+		db.insert(b"first", &[0, 0, 0]);
+		db.insert(b"second", &[0, 0, 1]);
+		let stats = db.index_stats();
+		assert_eq!((stats.index_bits, stats.watermarks), (16, (0, 0)));
+		assert_eq!(db.get(&[0, 0, 0]), Some(b"first".to_vec()));
+		assert_eq!(db.get(&[0, 0, 1]), Some(b"second".to_vec()));
+	}
+
+	#[test]
+	fn compaction_works() {
+		init();
+		let path = PathBuf::from("/tmp/test-compaction_works");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: u32| {
+			let mut v = vec![0u8; 4000];
+			v[..4].copy_from_slice(&i.to_le_bytes());
+			v
+		};
+		let keys = {
+			let mut db = Options::new()
+				.key_bytes(4)
+				.index_bits(12)
+				.path(path.clone())
+				.open::<Key>()
+				.unwrap();
+			// 4KB items have 512 to a table, so this will take three tables.
+			let keys = (0..1100).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+			assert!(path.join("44-2.content").is_file());
+			for (i, key) in keys.iter().enumerate() {
+				if i % 10 != 0 {
+					db.remove(key).unwrap();
+				}
+			}
+			// Bump one so we can check ref counts survive the move.
+			db.store(&value(1090));
+
+			let bytes_used = |db: &Database<Key>| db.info().iter().map(|x| (x.1).2).sum::<usize>();
+			let before = bytes_used(&db);
+			assert!(db.compact() > 0);
+			assert!(bytes_used(&db) < before / 5);
+			keys
+		};
+
+		assert!(path.join("44-0.content").is_file());
+		assert!(!path.join("44-1.content").is_file());
+		assert!(!path.join("44-2.content").is_file());
+
+		let db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		for (i, key) in keys.iter().enumerate() {
+			if i % 10 == 0 {
+				assert_eq!(db.get(key).unwrap(), value(i as u32));
+			} else {
+				assert!(!db.contains_key(key));
+			}
+		}
+		assert_eq!(db.get_ref_count(&keys[1090]), 2);
+	}
+
+	#[test]
+	fn allocation_prefers_fullest_table() {
+		init();
+		let path = PathBuf::from("/tmp/test-allocation_prefers_fullest_table");
+		let _ = std::fs::remove_dir_all(&path);
+
+		type Key = Blake2Output<[u8; 8]>;
+		let value = |i: u32| {
+			let mut v = vec![0u8; 4000];
+			v[..4].copy_from_slice(&i.to_le_bytes());
+			v
+		};
+		let mut db = Options::new()
+			.key_bytes(4)
+			.index_bits(12)
+			.path(path.clone())
+			.open::<Key>()
+			.unwrap();
+		// Two full tables of 512 4KB items.
+		let keys = (0..1024).map(|i| db.store(&value(i)).1).collect::<Vec<_>>();
+		let used = |db: &Database<Key>| db.info().iter().map(|x| (x.1).1).collect::<Vec<_>>();
+		assert_eq!(used(&db), vec![512, 512]);
+
+		keys[1000..].iter().for_each(|k| { db.remove(k).unwrap(); });
+		keys[0..10].iter().for_each(|k| { db.remove(k).unwrap(); });
+		assert_eq!(used(&db), vec![502, 488]);
+
+		(2000..2005).for_each(|i| { db.store(&value(i)); });
+		assert_eq!(used(&db), vec![507, 488]);
+
+		// Once reopened, a table which has lost items since is no longer the fullest.
+		drop(db);
+		let mut db = Options::from_path(path.clone()).open::<Key>().unwrap();
+		keys[10..30].iter().for_each(|k| { db.remove(k).unwrap(); });
+		(3000..3003).for_each(|i| { db.store(&value(i)); });
+		assert_eq!(used(&db), vec![487, 491]);
+	}
+
 	#[test]
 	fn general_use_should_work() {
 		init();
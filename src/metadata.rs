@@ -1,53 +1,287 @@
 use parity_scale_codec::{self as codec, Encode, Decode};
-use std::path::PathBuf;
-use crate::{Error, database::Options};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use log::info;
+use crate::{Error, database::Options, datum_size::SizeScheme, table::RefCount};
 
-type Version = u32;
+pub type Version = u32;
 
-const CURRENT_VERSION: Version = 1;
+const CURRENT_VERSION: Version = 2;
 
-pub struct MetadataV1 {
+/// The tunable parameters of a database, which are recorded so that they don't depend on whoever
+/// opens it next.
+#[derive(Clone, PartialEq, Eq, Debug, Encode, Decode)]
+pub struct Tunables {
+	/// Reindex once an insertion skips over an index entry this many times.
+	pub(crate) skipped_count_trigger: u8,
+	/// Reindex once an insertion lands this far from its primary index entry.
+	pub(crate) key_correction_trigger: u32,
+	/// Unmap oversize items once this many bytes of them are mapped.
+	pub(crate) oversize_trigger_mapped: u64,
+	/// Leave at most this many bytes of oversize items mapped after unmapping.
+	pub(crate) oversize_shrink_mapped: u64,
+	/// The minimum number of items for which each content table has space on disk.
+	pub(crate) min_items_backed: u32,
+}
+
+impl Default for Tunables {
+	fn default() -> Self {
+		Self {
+			skipped_count_trigger: 240,
+			key_correction_trigger: 32,
+			oversize_trigger_mapped: 256 * 1024 * 1024,
+			oversize_shrink_mapped: 64 * 1024 * 1024,
+			min_items_backed: 8,
+		}
+	}
+}
+
+/// The optional features of the format which this version understands. A database using any other
+/// may not be opened.
+pub const SUPPORTED_FEATURES: u64 = 0;
+
+pub struct MetadataV2 {
 	pub(crate) key_bytes: usize,
 	pub(crate) index_bits: usize,
+	/// Where the index lives, if not in the main database path.
+	pub(crate) index_path: Option<PathBuf>,
+	/// Where the sized content tables live, if not in the main database path.
+	pub(crate) content_path: Option<PathBuf>,
+	/// Where the oversize content table and its items live, if not in the main database path.
+	pub(crate) oversize_path: Option<PathBuf>,
+	/// The size classes and geometry of the content tables.
+	pub(crate) size_scheme: SizeScheme,
+	/// A repack of the content into a new size scheme which is yet to finish.
+	pub(crate) repack: Option<Repack>,
+	/// The length of the keys, if recorded. Databases created before it was recorded take it from
+	/// the key type with which they are next opened.
+	pub(crate) key_length: Option<u32>,
+	/// An identifier of the hasher from which the keys come, if one was given.
+	pub(crate) hasher: Option<String>,
+	pub(crate) tunables: Tunables,
+	/// The optional features of the format which the database uses, as bit flags.
+	pub(crate) features: u64,
+}
+
+/// The metadata of databases before version 2, which held just the geometry of the index. It's
+/// only ever read, in order to upgrade it.
+#[derive(Decode)]
+pub struct MetadataV1 {
+	key_bytes: u32,
+	index_bits: u32,
+}
+
+/// How far a repack has got.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum RepackStage {
+	/// Items are being moved into the new tables.
+	Moving,
+	/// Every item has been moved, and the old tables are being deleted.
+	Deleting,
+	/// The old tables are gone, and the new tables are being moved into their place.
+	Renaming,
+}
+
+/// A repack of the content into a new size scheme.
+#[derive(Clone, PartialEq, Eq, Debug, Encode, Decode)]
+pub struct Repack {
+	/// The scheme being repacked into.
+	pub(crate) scheme: SizeScheme,
+	pub(crate) stage: RepackStage,
+}
+
+/// How far a merge from another database has got, recorded in the target so that an interrupted
+/// merge may be resumed.
+#[derive(Clone, PartialEq, Eq, Debug, Encode, Decode)]
+pub struct MergeCheckpoint {
+	/// The path of the database being merged from.
+	pub(crate) source: String,
+	/// How many of its items, in the order in which they're stored, had been merged before the
+	/// batch now being merged.
+	pub(crate) merged: u64,
+	/// The key of each item of the batch, along with the number of references which the item
+	/// had in the target before the batch, so that a partly merged batch may be undone.
+	pub(crate) prior_ref_counts: Vec<(Vec<u8>, RefCount)>,
 }
 
-impl Metadata for MetadataV1 {}
+impl MergeCheckpoint {
+	fn filename(path: &Path) -> PathBuf {
+		path.join("merge.subdb")
+	}
+
+	/// Read the checkpoint of the database in `path`, if there is one.
+	pub fn read(path: &Path) -> Result<Option<Self>, Error> {
+		let filename = Self::filename(path);
+		if !filename.is_file() {
+			return Ok(None);
+		}
+		let checkpoint = std::fs::read(&filename)?;
+		let (magic, checkpoint) = <([u8; 4], Self)>::decode(&mut &checkpoint[..]).map_err(|_| Error::BadMetadata)?;
+		if &magic != b"SBDM" {
+			return Err(Error::BadMetadata);
+		}
+		Ok(Some(checkpoint))
+	}
 
-impl Decode for MetadataV1 {
+	/// Write the checkpoint into `path`, replacing any earlier one in a single step.
+	pub fn write(&self, path: &Path) -> Result<(), Error> {
+		(b"SBDM", self).using_encoded(|e| write_atomically(&Self::filename(path), e))
+	}
+
+	/// Remove any checkpoint from `path`, once the merge is finished.
+	pub fn clear(path: &Path) -> Result<(), Error> {
+		let filename = Self::filename(path);
+		if filename.is_file() {
+			std::fs::remove_file(filename)?;
+		}
+		Ok(())
+	}
+}
+
+/// Replace the contents of `filename` with `data` in a single step: they're written to a temporary
+/// file which is then renamed over it, and the directory is synced so that the rename survives a
+/// crash.
+fn write_atomically(filename: &Path, data: &[u8]) -> Result<(), Error> {
+	let temp_filename = filename.with_extension("subdb.tmp");
+	let mut file = File::create(&temp_filename)?;
+	file.write_all(data)?;
+	file.sync_all()?;
+	std::fs::rename(temp_filename, filename)?;
+	#[cfg(unix)]
+	{
+		if let Some(dir) = filename.parent() {
+			File::open(dir)?.sync_all()?;
+		}
+	}
+	Ok(())
+}
+
+/// Paths are kept as the raw bytes of the OS string, which is encoded exactly as a `String` would
+/// be if it is valid UTF-8.
+#[cfg(unix)]
+fn encode_path(path: &Option<PathBuf>) -> Option<Vec<u8>> {
+	use std::os::unix::ffi::OsStrExt;
+	path.as_ref().map(|p| p.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(unix)]
+fn decode_path(path: Option<Vec<u8>>) -> Option<PathBuf> {
+	use std::os::unix::ffi::OsStrExt;
+	path.map(|p| PathBuf::from(std::ffi::OsStr::from_bytes(&p)))
+}
+
+#[cfg(not(unix))]
+fn encode_path(path: &Option<PathBuf>) -> Option<Vec<u8>> {
+	path.as_ref().map(|p| p.to_string_lossy().into_owned().into_bytes())
+}
+
+#[cfg(not(unix))]
+fn decode_path(path: Option<Vec<u8>>) -> Option<PathBuf> {
+	path.map(|p| PathBuf::from(String::from_utf8_lossy(&p).into_owned()))
+}
+
+impl From<MetadataV1> for MetadataV2 {
+	fn from(m: MetadataV1) -> Self {
+		Self {
+			key_bytes: m.key_bytes as usize,
+			index_bits: m.index_bits as usize,
+			index_path: None,
+			content_path: None,
+			oversize_path: None,
+			size_scheme: SizeScheme::default(),
+			repack: None,
+			key_length: None,
+			hasher: None,
+			tunables: Tunables::default(),
+			features: 0,
+		}
+	}
+}
+
+impl Decode for MetadataV2 {
 	fn decode<I: codec::Input>(input: &mut I) -> Result<Self, codec::Error> {
 		Ok(Self {
 			key_bytes: u32::decode(input)? as usize,
 			index_bits: u32::decode(input)? as usize,
+			index_path: decode_path(Decode::decode(input)?),
+			content_path: decode_path(Decode::decode(input)?),
+			oversize_path: decode_path(Decode::decode(input)?),
+			size_scheme: Decode::decode(input)?,
+			repack: Decode::decode(input)?,
+			key_length: Decode::decode(input)?,
+			hasher: Decode::decode(input)?,
+			tunables: Decode::decode(input)?,
+			features: Decode::decode(input)?,
 		})
 	}
 }
 
-impl Encode for MetadataV1 {
+impl Encode for MetadataV2 {
 	fn encode_to<O: codec::Output>(&self, dest: &mut O) {
 		(self.key_bytes as u32).encode_to(dest);
 		(self.index_bits as u32).encode_to(dest);
+		encode_path(&self.index_path).encode_to(dest);
+		encode_path(&self.content_path).encode_to(dest);
+		encode_path(&self.oversize_path).encode_to(dest);
+		self.size_scheme.encode_to(dest);
+		self.repack.encode_to(dest);
+		self.key_length.encode_to(dest);
+		self.hasher.encode_to(dest);
+		self.tunables.encode_to(dest);
+		self.features.encode_to(dest);
+	}
+}
+
+impl MetadataV2 {
+	/// The directory holding the index, given the main database `path`, against which a relative
+	/// location is resolved.
+	pub fn index_path(&self, path: &Path) -> PathBuf {
+		self.index_path.as_ref().map_or_else(|| path.to_path_buf(), |p| path.join(p))
+	}
+
+	/// The directory holding the sized content tables, given the main database `path`, against
+	/// which a relative location is resolved.
+	pub fn content_path(&self, path: &Path) -> PathBuf {
+		self.content_path.as_ref().map_or_else(|| path.to_path_buf(), |p| path.join(p))
+	}
+
+	/// The directory holding the oversize content table and its items, given the main database
+	/// `path`, against which a relative location is resolved.
+	pub fn oversize_path(&self, path: &Path) -> PathBuf {
+		self.oversize_path.as_ref().map_or_else(|| path.to_path_buf(), |p| path.join(p))
+	}
+
+	/// The size scheme of the content tables.
+	pub fn size_scheme(&self) -> SizeScheme {
+		self.size_scheme.clone()
 	}
 }
 
 pub trait Metadata: Encode + Decode {
-	fn filename(path: &PathBuf) -> PathBuf {
-		let mut filename = path.clone();
-		filename.push("metadata.subdb");
-		filename
+	fn filename(path: &Path) -> PathBuf {
+		path.join("metadata.subdb")
 	}
 
-	fn write(&self, path: &PathBuf) -> Result<(), Error> {
-		(b"SBDB", CURRENT_VERSION, &self)
-			.using_encoded(|e| std::fs::write(Self::filename(path), e))?;
-		Ok(())
+	/// Write the metadata into `path`. It's written to a temporary file which then replaces the
+	/// old one, so the metadata is never left half-written.
+	fn write(&self, path: &Path) -> Result<(), Error> {
+		(b"SBDB", CURRENT_VERSION, &self).using_encoded(|e| write_atomically(&Self::filename(path), e))
 	}
 
-	fn try_read(path: &PathBuf) -> Result<Option<Self>, Error> {
+	/// Decode metadata of an older `version`.
+	fn decode_old(_version: Version, _input: &mut &[u8]) -> Result<Self, Error> {
+		Err(Error::UnsupportedVersion)
+	}
+
+	/// Read the metadata in `path`, if there is any, along with the version in which it was
+	/// written. Metadata of an older version is upgraded, but only in memory.
+	fn read_only(path: &Path) -> Result<Option<(Self, Version)>, Error> {
 		let filename = Self::filename(path);
 		if !filename.is_file() {
 			return Ok(None);
 		}
-		let metadata = std::fs::read(Self::filename(path))?;
+		let metadata = std::fs::read(&filename)?;
 		let mut input = &metadata[..];
 
 		let magic = <[u8; 4]>::decode(&mut input).map_err(|_| Error::BadMetadata)?;
@@ -55,18 +289,51 @@ pub trait Metadata: Encode + Decode {
 			return Err(Error::BadMetadata);
 		}
 		let version = Version::decode(&mut input).map_err(|_| Error::BadMetadata)?;
-		if version != CURRENT_VERSION {
-			return Err(Error::UnsupportedVersion);
+		let metadata = match version {
+			CURRENT_VERSION => Self::decode(&mut input).map_err(|_| Error::BadMetadata)?,
+			v if v > CURRENT_VERSION => return Err(Error::UnsupportedVersion),
+			v => Self::decode_old(v, &mut input)?,
+		};
+		Ok(Some((metadata, version)))
+	}
+
+	/// Read the metadata in `path`, if there is any. Metadata of an older version is upgraded and
+	/// written back.
+	fn try_read(path: &Path) -> Result<Option<Self>, Error> {
+		Ok(match Self::read_only(path)? {
+			Some((metadata, version)) if version != CURRENT_VERSION => {
+				info!("Upgrading SubDB metadata from version {} to {}", version, CURRENT_VERSION);
+				metadata.write(path)?;
+				Some(metadata)
+			}
+			r => r.map(|(metadata, _)| metadata),
+		})
+	}
+}
+
+impl Metadata for MetadataV2 {
+	fn decode_old(version: Version, input: &mut &[u8]) -> Result<Self, Error> {
+		match version {
+			1 => Ok(MetadataV1::decode(input).map_err(|_| Error::BadMetadata)?.into()),
+			_ => Err(Error::UnsupportedVersion),
 		}
-		Ok(Some(Self::decode(&mut input).map_err(|_| Error::BadMetadata)?))
 	}
 }
 
-impl<'a> From<&'a Options> for MetadataV1 {
+impl<'a> From<&'a Options> for MetadataV2 {
 	fn from(o: &'a Options) -> Self {
 		Self {
 			key_bytes: o.key_bytes,
 			index_bits: o.index_bits,
+			index_path: o.index_path.clone(),
+			content_path: o.content_path.clone(),
+			oversize_path: o.oversize_path.clone(),
+			size_scheme: o.size_scheme.clone().unwrap_or_default(),
+			repack: None,
+			key_length: None,
+			hasher: o.hasher.clone(),
+			tunables: o.tunables(Tunables::default()),
+			features: 0,
 		}
 	}
-}
\ No newline at end of file
+}
@@ -1,4 +1,5 @@
 use parking_lot::RwLock;
+use log::error;
 use blake2_rfc::blake2b::blake2b;
 use sp_database::{self, ColumnId};
 use parity_scale_codec::Encode;
@@ -37,7 +38,9 @@ impl<H: KeyType> sp_database::Database<H> for SafeDatabase<H> {
 		(col, key).using_encoded(|d|
 			hash.as_mut().copy_from_slice(blake2b(32, &[], d).as_bytes())
 		);
-		self.0.write().insert(&value, &hash);
+		if let Err(e) = self.0.write().try_insert(&value, &hash) {
+			error!(target: "database", "Unable to store value of column {}: {}", col, e);
+		}
 	}
 
 	fn remove(&self, col: ColumnId, key: &[u8]) {
@@ -57,7 +60,9 @@ impl<H: KeyType> sp_database::Database<H> for SafeDatabase<H> {
 	}
 
 	fn store(&self, hash: &H, preimage: &[u8]) {
-		self.0.write().insert(preimage, hash);
+		if let Err(e) = self.0.write().try_insert(preimage, hash) {
+			error!(target: "database", "Unable to store preimage: {}", e);
+		}
 	}
 
 	fn release(&self, hash: &H) {
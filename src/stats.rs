@@ -0,0 +1,98 @@
+use crate::datum_size::DatumSize;
+
+/// How the items of one size class use their space.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClassStats {
+	/// The size class.
+	pub datum_size: DatumSize,
+	/// The space for the value of each item, or `None` if the items are oversize.
+	pub value_size: Option<usize>,
+	/// The number of content tables of the size class.
+	pub tables: usize,
+	/// The number of items of the size class.
+	pub items: u64,
+	/// The bytes taken by the values themselves.
+	pub value_bytes: u64,
+	/// The bytes of value space which the values leave unused.
+	pub padding_bytes: u64,
+	/// The bytes taken by the reference count, size correction and key of each item.
+	pub header_bytes: u64,
+	/// How many items there are of each value size, in order of size, as for
+	/// `Database::value_sizes`.
+	pub value_sizes: Vec<(u32, u32)>,
+}
+
+/// The health of an index. See `Database::index_stats`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IndexStats {
+	/// The number of key bytes held in the index and the number of bits of them which position
+	/// entries.
+	pub key_bytes: usize,
+	pub index_bits: usize,
+	/// The number of positions in the index.
+	pub slots: usize,
+	/// The number of positions holding an entry.
+	pub occupied: usize,
+	/// How many entries lie each number of positions past that which they'd ideally have, in order
+	/// of distance. This is how many extra positions a lookup of them must read.
+	pub key_corrections: Vec<(usize, usize)>,
+	/// How many positions were skipped over by each number of entries, in order of that number.
+	/// Positions which nothing has skipped aren't counted.
+	pub skipped_counts: Vec<(u8, usize)>,
+	/// The greatest number of entries to have skipped over any one position.
+	pub max_skipped_count: u8,
+	/// The longest run of consecutive occupied positions; a lookup for a missing key which lands
+	/// in a run may have to read to its end.
+	pub longest_cluster: usize,
+	/// The greatest skipped count and key correction caused by an insertion since the index was
+	/// built or opened.
+	pub watermarks: (u8, usize),
+	/// The skipped count and key correction beyond which an insertion causes a reindex.
+	pub triggers: (u8, usize),
+}
+
+impl IndexStats {
+	/// The proportion of positions holding an entry.
+	pub fn load_factor(&self) -> f64 {
+		self.occupied as f64 / self.slots as f64
+	}
+
+	/// The mean number of extra positions which a lookup of an entry reads.
+	pub fn mean_key_correction(&self) -> f64 {
+		let total = self.key_corrections.iter().map(|(c, n)| c * n).sum::<usize>();
+		total as f64 / self.occupied.max(1) as f64
+	}
+}
+
+/// How a database uses its space. See `Database::stats`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Stats {
+	/// The statistics of each size class which has any content tables, in order of size class.
+	pub classes: Vec<ClassStats>,
+	/// The bytes taken by each entry of the index.
+	pub index_entry_bytes: usize,
+}
+
+impl Stats {
+	/// The number of items.
+	pub fn items(&self) -> u64 {
+		self.classes.iter().map(|c| c.items).sum()
+	}
+
+	/// The bytes taken by the values themselves.
+	pub fn value_bytes(&self) -> u64 {
+		self.classes.iter().map(|c| c.value_bytes).sum()
+	}
+
+	/// The mean number of bytes which each item takes beyond its value: its header, its padding
+	/// and its index entry. Empty index entries aren't counted.
+	pub fn overhead_per_item(&self) -> f64 {
+		let items = self.items();
+		if items == 0 {
+			return 0.0
+		}
+		let overhead = self.classes.iter().map(|c| c.padding_bytes + c.header_bytes).sum::<u64>()
+			+ items * self.index_entry_bytes as u64;
+		overhead as f64 / items as f64
+	}
+}
@@ -1,36 +1,47 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::{Relaxed, Acquire, Release}};
 use std::ops::{Deref, DerefMut};
+use std::convert::TryInto;
 use parking_lot::{
 	RwLock, RwLockWriteGuard, RwLockReadGuard, MappedRwLockReadGuard, RwLockUpgradableReadGuard
 };
-use log::{trace, debug};
-use memmap::{MmapMut, MmapOptions};
+use log::{trace, debug, info};
+use memmap::{Mmap, MmapMut, MmapOptions};
 use parity_scale_codec::{self as codec, Encode, Decode};
-use crate::types::{KeyType, SimpleWriter};
-use crate::datum_size::DatumSize;
+use crate::types::{KeyType, SimpleWriter, key_length};
+use crate::datum_size::{DatumSize, SizeScheme};
+use crate::freemap::FreeMap;
+use crate::Error;
 
 /// How many references a storage table item has.
 pub type RefCount = u16;
 
+/// The most references that a storage table item may have.
+pub const MAX_REF_COUNT: RefCount = 32767;
+
 /// Where in a storage table an item is.
-pub type TableItemIndex = u16;
+pub type TableItemIndex = u32;
 
-/// How many table items; must be able to store a range from 0 to TableItemIndex::max_value() + 1
-/// inclusive, therefore needs the next biggest type up.
+/// How many table items; must be able to store a range from 0 to `MAX_TABLE_ENTRIES` inclusive.
 pub type TableItemCount = u32;
 
 /// A time index for our LRU system.
 pub type LruIndex = AtomicU64;
 
+/// The granularity with which we give disk space back to the filesystem.
+const PAGE_SIZE: usize = 4096;
+
 pub struct Table<K> {
 	file: File,
 	path: PathBuf,
 	data: RwLock<MmapMut>,
 	header_data: RwLock<MmapMut>,
 	header: TableHeader,
+	/// Which slots are allocated; persisted in the file just after the header.
+	allocated: FreeMap,
 	item_header_size: usize,
 	item_size: usize,
 	item_count: TableItemCount,
@@ -45,21 +56,123 @@ pub struct Table<K> {
 	_dummy: std::marker::PhantomData<K>,
 }
 
+/// Identifies a file as a content table.
+const TABLE_MAGIC: [u8; 4] = *b"SBDT";
+
+/// The version of the content table format.
+const TABLE_VERSION: u32 = 1;
+
+/// The space reserved at the start of a content table for its `TableDescriptor`.
+const DESCRIPTOR_SIZE: usize = 32;
+
+/// Describes how a content table is laid out. It's written when the table is created and checked
+/// each time the table is opened, so that a table is never read with the wrong layout.
+///
+/// In the file, this is followed by the `TableHeader`.
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, Debug)]
+struct TableDescriptor {
+	magic: [u8; 4],
+	version: u32,
+	/// The `DatumSize` of the table's items.
+	size_class: u8,
+	/// The length of the key of each item.
+	key_size: u32,
+	/// The length of the value of each item, or zero if the items are oversize.
+	value_size: u32,
+	/// The number of bytes used for the size correction of each item.
+	correction_factor_size: u8,
+	/// The number of items that the table may hold.
+	item_count: TableItemCount,
+}
+
 /// Rather unsafe.
+///
+/// In the file, this is followed by the bitmap of allocated slots, one bit per possible item
+/// (rounded up to a whole number of 64-bit little-endian words), and then the items themselves.
 #[derive(Clone, Copy, Encode, Decode, Debug)]
 struct TableHeader {
-	/// The number of items used. Never more than `touched_count`.
+	/// The number of items used. Never more than `touched_count`. Always equal to the number of
+	/// bits set in the bitmap.
 	used: TableItemCount,
-	/// Ignore if used == touched_count; otherwise it is the next free item.
-	next_free: TableItemIndex,
 	/// The number of unique slots that have been allocated at some point. Never more than
 	/// `item_count`.
 	///
 	/// Item indices equal to this and less than `item_count` may be allocated in addition to the
-	/// linked list starting at `next_free`.
+	/// slots below it which are clear in the bitmap. No bits at or above it may be set.
 	touched_count: TableItemCount,
 	/// Total amount of bytes in all external files. Only matters when size is > 0
 	external_data: u64,
+	/// How many slots have been freed since we last punched holes for free slots. It's kept here
+	/// so that slots freed shortly before the table was closed still count towards the next time.
+	freed_since_punch: TableItemCount,
+	/// How many times an item has been freed from the table or moved into a slot of it. It only
+	/// ever grows, so that a cursor may tell whether any item it has walked past could have gone
+	/// elsewhere.
+	changes: u64,
+}
+
+/// The header of a content table written before tables described themselves. It sat at the very
+/// start of the file and the items followed it; free slots were kept in a list threaded through
+/// them.
+#[derive(Clone, Copy, Encode, Decode, Debug)]
+struct UnversionedTableHeader {
+	used: TableItemCount,
+	next_free: u16,
+	touched_count: TableItemCount,
+	external_data: u64,
+}
+
+/// The space that an `UnversionedTableHeader` took up in the file.
+const UNVERSIONED_HEADER_SIZE: usize = 24;
+
+/// What we need to know of a table written in an older format in order to migrate it.
+struct OldLayout {
+	/// How many slots have been allocated at some point.
+	touched_count: TableItemCount,
+	/// Total amount of bytes in all external files.
+	external_data: u64,
+	/// The offset in the file of the first item.
+	items_offset: usize,
+}
+
+/// The offset of the bitmap in a table's file.
+fn bitmap_offset() -> usize {
+	DESCRIPTOR_SIZE + size_of::<TableHeader>()
+}
+
+/// The number of bytes needed for the bitmap of a table with `item_count` slots.
+fn bitmap_size(item_count: TableItemCount) -> usize {
+	((item_count as usize + 63) >> 6) * 8
+}
+
+/// Check that the bitmap of the table at `path` agrees with its `header` and with the headers of
+/// the items, as read by `item_header`, returning a description of each problem found.
+fn check_slots<K: AsRef<[u8]> + AsMut<[u8]> + Default>(
+	path: &Path,
+	header: &TableHeader,
+	allocated: &FreeMap,
+	item_header: impl Fn(TableItemIndex) -> Option<ItemHeader<K>>,
+) -> Vec<String> {
+	let mut problems = vec![];
+	let touched = header.touched_count as usize;
+	for i in 0..touched {
+		match (allocated.get(i), item_header(i as TableItemIndex)) {
+			(true, Some(ItemHeader::Free)) =>
+				problems.push(format!("{}: slot {} is marked allocated but is free", path.display(), i)),
+			(false, Some(ItemHeader::Allocated {..})) =>
+				problems.push(format!("{}: slot {} is marked free but is allocated", path.display(), i)),
+			_ => {}
+		}
+	}
+	let marked = (0..touched).filter(|&i| allocated.get(i)).count();
+	if marked != allocated.total_set() {
+		problems.push(format!("{}: slots beyond {} are marked allocated", path.display(), touched));
+	}
+	if marked != header.used as usize {
+		problems.push(format!("{}: {} slots are marked allocated but {} are used",
+			path.display(), marked, header.used));
+	}
+	problems
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -70,6 +183,19 @@ enum CorrectionFactor {
 	U32,
 }
 
+impl CorrectionFactor {
+	/// The correction factor which takes `size` bytes, if there is one.
+	fn from_size(size: u8) -> Option<Self> {
+		match size {
+			0 => Some(CorrectionFactor::None),
+			1 => Some(CorrectionFactor::U8),
+			2 => Some(CorrectionFactor::U16),
+			4 => Some(CorrectionFactor::U32),
+			_ => None,
+		}
+	}
+}
+
 #[derive(Clone, Debug)]
 enum ItemHeader<K: AsRef<[u8]> + AsMut<[u8]> + Default> {
 	Allocated {
@@ -79,21 +205,45 @@ enum ItemHeader<K: AsRef<[u8]> + AsMut<[u8]> + Default> {
 		size_correction: u32,
 		key: K,
 	},
-	Free(
-		/// If `used < touched_count`, then the next free item's index. If the two are equal, then
-		/// this is undefined.
-		TableItemIndex,
-	),
+	/// Not allocated. Free items carry no information, so runs of them can be punched out of the
+	/// file entirely.
+	Free,
 }
 
-impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
-	fn as_next_free(&self) -> TableItemIndex {
-		match self {
-			ItemHeader::Free(next_free) => *next_free,
-			ItemHeader::Allocated {..} => panic!("Free expected. Database corruption?"),
-		}
-	}
+/// Deallocate `len` bytes of `file` from `offset`, so that they read as zeros and take no space on
+/// disk.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+	use std::os::unix::io::AsRawFd;
+	let r = unsafe {
+		libc::fallocate(
+			file.as_raw_fd(),
+			libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+			offset as libc::off_t,
+			len as libc::off_t,
+		)
+	};
+	if r == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: u64) -> std::io::Result<()> {
+	Err(std::io::ErrorKind::Other.into())
+}
+
+/// The number of bytes that `file` really has allocated on disk, if the platform can tell us.
+#[cfg(unix)]
+fn allocated_bytes(file: &File) -> Option<usize> {
+	use std::os::unix::fs::MetadataExt;
+	file.metadata().ok().map(|m| m.blocks() as usize * 512)
+}
 
+#[cfg(not(unix))]
+fn allocated_bytes(_file: &File) -> Option<usize> {
+	None
+}
+
+impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 	fn as_allocation(&self, check_hash: Option<&K>) -> Result<(RefCount, usize), ()> {
 		match self {
 			ItemHeader::Allocated { ref_count, size_correction, key } => {
@@ -103,7 +253,7 @@ impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 					Err(())
 				}
 			},
-			ItemHeader::Free(_) => panic!("Allocated expected. Database corruption?"),
+			ItemHeader::Free => panic!("Allocated expected. Database corruption?"),
 		}
 	}
 
@@ -111,11 +261,16 @@ impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 	fn to_maybe_key(self) -> Option<K> {
 		match self {
 			ItemHeader::Allocated { key, .. } => Some(key),
-			ItemHeader::Free(_) => None,
+			ItemHeader::Free => None,
 		}
 	}
 
 	fn decode<I: codec::Input>(input: &mut I, correction_factor: CorrectionFactor) -> Result<Self, codec::Error> {
+		Self::decode_into(input, correction_factor, K::default())
+	}
+
+	/// Decode a header whose key is read into `key`, which must already be of the right length.
+	fn decode_into<I: codec::Input>(input: &mut I, correction_factor: CorrectionFactor, mut key: K) -> Result<Self, codec::Error> {
 		let first_byte = input.read_byte()?;
 		Ok(if first_byte > 0 {
 			let second_byte = input.read_byte()? as u16;
@@ -126,11 +281,10 @@ impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 				CorrectionFactor::U16 => u16::decode(input)? as u32,
 				CorrectionFactor::U32 => u32::decode(input)?,
 			};
-			let mut key = K::default();
 			input.read(key.as_mut())?;
 			Self::Allocated { ref_count, size_correction, key }
 		} else {
-			Self::Free(TableItemIndex::decode(input)?)
+			Self::Free
 		})
 	}
 
@@ -149,8 +303,8 @@ impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 				}
 				output.write(key.as_ref());
 			}
-			ItemHeader::Free(index) => {
-				(0u8, index).encode_to(output);
+			ItemHeader::Free => {
+				0u8.encode_to(output);
 			}
 		}
 	}
@@ -158,78 +312,288 @@ impl<K: AsRef<[u8]> + AsMut<[u8]> + Default + Eq> ItemHeader<K> {
 
 impl<K: KeyType> Table<K> {
 	pub fn commit(&mut self) {
+		self.punch_holes();
 		self.data.write().flush().expect("I/O Error");
 	}
 
-	pub fn open(path: PathBuf, datum_size: DatumSize, min_items_backed: TableItemCount) -> Self {
+	/// Give the disk space behind free slots back to the filesystem, wherever there are enough of
+	/// them in a row to cover whole pages. Does nothing unless at least a page's worth of slots
+	/// was freed since the last time.
+	pub fn punch_holes(&mut self) {
+		if self.value_size == 0 || self.header.freed_since_punch as usize * self.item_size < PAGE_SIZE {
+			return
+		}
+		let mut h = self.header;
+		h.freed_since_punch = 0;
+		self.set_header(h);
+
+		let mut punched = 0;
+		let mut runs = self.allocated.iter_free(self.header.touched_count as usize).peekable();
+		while let Some(begin) = runs.next() {
+			let mut end = begin;
+			while runs.peek() == Some(&(end + 1)) {
+				end = runs.next().expect("peeked; qed");
+			}
+			let offset = self.table_header_size + self.item_size * begin;
+			let limit = self.table_header_size + self.item_size * (end + 1);
+			let offset = offset + (PAGE_SIZE - offset % PAGE_SIZE) % PAGE_SIZE;
+			let limit = limit / PAGE_SIZE * PAGE_SIZE;
+			if limit > offset {
+				if let Err(e) = punch_hole(&self.file, offset as u64, (limit - offset) as u64) {
+					debug!(target: "table", "Cannot punch holes: {}", e);
+					return
+				}
+				punched += limit - offset;
+			}
+		}
+		trace!(target: "table", "Punched {} bytes from {}", punched, self.path.display());
+	}
+
+	/// Open the table at `path`, creating it if it doesn't exist. Fails if the table was created
+	/// with a different layout to that given by `datum_size`, `scheme` and our key type.
+	pub fn open(path: PathBuf, datum_size: DatumSize, scheme: &SizeScheme, min_items_backed: TableItemCount) -> Result<Self, Error> {
 		assert!(!path.exists() || path.is_file(), "Path must either not exist or be a file.");
 
-		let file = OpenOptions::new()
+		let mut file = OpenOptions::new()
 			.read(true)
 			.write(true)
 			.create(true)
-			.open(&path)
-			.expect("Path must be writable.");
-		let len = file.metadata().expect("File must be readable").len();
-		let value_size = datum_size.size().unwrap_or(0);
-		let (correction_factor, correction_factor_size) = match datum_size.size_range().unwrap_or(0) {
+			.open(&path)?;
+		let len = file.metadata()?.len();
+		let value_size = scheme.size(datum_size).unwrap_or(0);
+		let (correction_factor, correction_factor_size) = match scheme.size_range(datum_size).unwrap_or(0) {
 			0 => (CorrectionFactor::None, 0),
 			1..=255 => (CorrectionFactor::U8, 1),
 			256..=65535 => (CorrectionFactor::U16, 2),
 			_ => (CorrectionFactor::U32, 4),
 		};
 		trace!(target: "table", "Table size correction: {:?}/{} bytes", correction_factor, correction_factor_size);
-		let item_count = datum_size.contents_entries() as TableItemCount;
-		let key_size = std::mem::size_of::<K>();
-		let item_header_size = (size_of::<RefCount>() + correction_factor_size + key_size)
-			.max(1 + size_of::<TableItemIndex>());
+		let item_count = scheme.contents_entries(datum_size) as TableItemCount;
+		let key_size = key_length::<K>();
+		let item_header_size = size_of::<RefCount>() + correction_factor_size + key_size;
 		let item_size = value_size + item_header_size;
 		trace!(target: "table", "Item size: {} bytes = rc {} + cfs {} + key {} + value {}", item_size, size_of::<RefCount>(), correction_factor_size, key_size, value_size);
-		let table_header_size = size_of::<TableHeader>();
-		let total_size = table_header_size + item_size * item_count as usize;
+		let table_header_size = bitmap_offset() + bitmap_size(item_count);
 		let minimum_size = table_header_size + item_size * item_count.min(min_items_backed) as usize;
 
-		assert!(
-			len == 0 || len >= minimum_size as u64 || len <= total_size as u64,
-			"File exists but length is unexpected"
-		);
+		let descriptor = TableDescriptor {
+			magic: TABLE_MAGIC,
+			version: TABLE_VERSION,
+			size_class: datum_size.into(),
+			key_size: key_size as u32,
+			value_size: value_size as u32,
+			correction_factor_size: correction_factor_size as u8,
+			item_count,
+		};
 		if len == 0 {
-			file.set_len(minimum_size as u64).expect("Path must be writable.");
+			file.set_len(minimum_size as u64)?;
+		} else {
+			if let Some(old) = Self::old_layout(&mut file, len, &descriptor, item_size)? {
+				file = Self::migrate(&path, &mut file, len, &old, &descriptor, item_size, table_header_size)?;
+			}
+			let len = file.metadata()?.len();
+			file.seek(SeekFrom::Start(0))?;
+			Self::check_descriptor(&path, &mut file, len, &descriptor, table_header_size)?;
 		}
 
-		let header_data = unsafe {
+		let mut header_data = unsafe {
 			MmapOptions::new()
 				.len(table_header_size)
-				.map_mut(&file)
-				.expect("Path must be writable.")
+				.map_mut(&file)?
 		};
 		let data = unsafe {
 			MmapOptions::new()
 				.offset(table_header_size as u64)
-				.map_mut(&file)
-				.expect("Path must be writable.")
+				.map_mut(&file)?
 		};
-		let header = TableHeader::decode(&mut header_data.as_ref())
+		if len == 0 {
+			descriptor.encode_to(&mut SimpleWriter(header_data.as_mut(), 0));
+		}
+		let header = TableHeader::decode(&mut &header_data[DESCRIPTOR_SIZE..])
 			.expect("Invalid table header. Database corruption?");
+		let allocated = FreeMap::new(header_data[bitmap_offset()..]
+			.chunks(8)
+			.map(|w| u64::from_le_bytes(w.try_into().expect("chunks of 8; qed")))
+			.collect()
+		);
 		trace!(target: "table", "Read header: {:?}", header);
 		let maps_count = if value_size == 0 { header.touched_count as usize } else { 0 };
 		let mut maps = Vec::new();
 		maps.resize_with(maps_count,|| None);
 		trace!(target: "table", "Maps is now: {} items: {:?}", maps.len(), maps);
 
-		Self {
-			path, file, data: RwLock::new(data), header_data: RwLock::new(header_data), header, item_count, item_size, item_header_size, value_size, correction_factor,
-			table_header_size, maps: RwLock::new(maps), lru_index: Default::default(), mapped: Default::default(), _dummy: Default::default()
+		Ok(Self {
+			path, file, data: RwLock::new(data), header_data: RwLock::new(header_data), header, allocated, item_count, item_size, item_header_size, value_size, correction_factor,
+			table_header_size, maps: RwLock::new(maps), lru_index: Default::default(), mapped: Default::default(),
+			_dummy: Default::default()
+		})
+	}
+
+	/// If the existing table `file`, of `len` bytes, was written in an older format which we can
+	/// migrate from, then how it is laid out. Tables of the current format, or which are not
+	/// tables we recognise at all, are left to `check_descriptor`.
+	///
+	/// The only older format is the original one, which had no descriptor; we know one when its
+	/// header is consistent with the layout that we expect.
+	fn old_layout(file: &mut File, len: u64, expected: &TableDescriptor, item_size: usize) -> Result<Option<OldLayout>, Error> {
+		let mut data = [0u8; DESCRIPTOR_SIZE];
+		if len < DESCRIPTOR_SIZE as u64 {
+			return Ok(None)
+		}
+		file.seek(SeekFrom::Start(0))?;
+		file.read_exact(&mut data)?;
+		let found = TableDescriptor::decode(&mut &data[..]).ok();
+		if matches!(found, Some(ref found) if found.magic == TABLE_MAGIC) {
+			return Ok(None)
+		}
+		let header = UnversionedTableHeader::decode(&mut &data[..])
+			.expect("Descriptor space is larger than the header; qed");
+		let items_backed = (len as usize).saturating_sub(UNVERSIONED_HEADER_SIZE) / item_size;
+		let consistent = header.used <= header.touched_count
+			&& header.touched_count <= expected.item_count
+			&& header.touched_count as usize <= items_backed
+			&& (header.used == header.touched_count || (header.next_free as TableItemCount) < header.touched_count);
+		Ok(if consistent {
+			Some(OldLayout {
+				touched_count: header.touched_count,
+				external_data: header.external_data,
+				items_offset: UNVERSIONED_HEADER_SIZE,
+			})
+		} else {
+			None
+		})
+	}
+
+	/// Rewrite the table `file` at `path`, of `len` bytes and laid out as `old`, in the current
+	/// format, returning the new file. Which slots are allocated is found from the first byte of
+	/// each item, which is zero only for free slots in every format.
+	///
+	/// The new table is written alongside the old and then replaces it, so the old table is
+	/// untouched if we fail part way.
+	fn migrate(
+		path: &Path,
+		file: &mut File,
+		len: u64,
+		old: &OldLayout,
+		descriptor: &TableDescriptor,
+		item_size: usize,
+		table_header_size: usize,
+	) -> Result<File, Error> {
+		info!(target: "table", "Migrating {} to table format version {}", path.display(), TABLE_VERSION);
+		let items_backed = (len as usize).saturating_sub(old.items_offset) / item_size;
+		let touched_count = old.touched_count.min(items_backed as TableItemCount);
+		let mut temp_path = path.to_path_buf();
+		temp_path.set_extension("migrating");
+		let mut new_file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.open(&temp_path)?;
+		new_file.set_len((table_header_size + items_backed * item_size) as u64)?;
+
+		let mut allocated = FreeMap::with_capacity(descriptor.item_count as usize);
+		file.seek(SeekFrom::Start(old.items_offset as u64))?;
+		new_file.seek(SeekFrom::Start(table_header_size as u64))?;
+		{
+			let mut reader = BufReader::new(&*file);
+			let mut writer = BufWriter::new(&new_file);
+			let mut item = vec![0u8; item_size];
+			for i in 0..touched_count as usize {
+				reader.read_exact(&mut item)?;
+				if item[0] > 0 {
+					allocated.set(i);
+				} else {
+					// Free slots carry nothing worth keeping.
+					item.iter_mut().for_each(|b| *b = 0);
+				}
+				writer.write_all(&item)?;
+			}
+			writer.flush()?;
+		}
+
+		let header = TableHeader {
+			used: allocated.total_set() as TableItemCount,
+			touched_count,
+			external_data: old.external_data,
+			freed_since_punch: 0,
+			changes: 0,
+		};
+		let mut head = descriptor.encode();
+		head.resize(DESCRIPTOR_SIZE, 0);
+		header.encode_to(&mut head);
+		head.resize(bitmap_offset(), 0);
+		for w in 0..bitmap_size(descriptor.item_count) / 8 {
+			head.extend_from_slice(&allocated.word(w).to_le_bytes());
+		}
+		new_file.seek(SeekFrom::Start(0))?;
+		new_file.write_all(&head)?;
+		new_file.sync_all()?;
+		std::fs::rename(&temp_path, path)?;
+		Ok(new_file)
+	}
+
+	/// Check that the existing table `file`, of `len` bytes, has the descriptor `expected`.
+	fn check_descriptor(
+		path: &Path,
+		file: &mut File,
+		len: u64,
+		expected: &TableDescriptor,
+		table_header_size: usize,
+	) -> Result<(), Error> {
+		let mismatch = |what: String| Err(Error::IncompatibleTable(format!("{}: {}", path.display(), what)));
+		let mut data = [0u8; DESCRIPTOR_SIZE];
+		if len < table_header_size as u64 || file.read_exact(&mut data).is_err() {
+			return mismatch("too short to be a content table".into())
+		}
+		let found = match TableDescriptor::decode(&mut &data[..]) {
+			Ok(found) if found.magic == TABLE_MAGIC => found,
+			_ => return mismatch("not a content table".into()),
+		};
+		let check = |what: &str, found: u64, expected: u64| if found != expected {
+			mismatch(format!("{} is {} but expected {}", what, found, expected))
+		} else {
+			Ok(())
+		};
+		check("format version", found.version as u64, expected.version as u64)?;
+		check("size class", found.size_class as u64, expected.size_class as u64)?;
+		check("key length", found.key_size as u64, expected.key_size as u64)?;
+		check("value size", found.value_size as u64, expected.value_size as u64)?;
+		check("size correction length", found.correction_factor_size as u64, expected.correction_factor_size as u64)?;
+		check("item count", found.item_count as u64, expected.item_count as u64)?;
+		Ok(())
+	}
+
+	/// Mark the slot `i` as allocated or free in the bitmap, both in memory and on disk.
+	fn mark(&mut self, i: TableItemIndex, allocated: bool) {
+		let i = i as usize;
+		if allocated {
+			self.allocated.set(i);
+		} else {
+			self.allocated.clear(i);
 		}
+		let offset = bitmap_offset() + i / 64 * 8;
+		self.header_data.write()[offset..offset + 8]
+			.copy_from_slice(&self.allocated.word(i / 64).to_le_bytes());
 	}
 
 	/// Extend the file, and also the amount mapped to hold twice as many items as it does currently
 	/// but no more than its maximum allowed `item_count`.
 	fn extend(&mut self, min_items: TableItemCount) {
-		self.item_count = ((self.data.read().len() / self.item_size * 2)
+		let items_backed = ((self.items_backed() as usize * 2)
 			.min(self.item_count as usize) as TableItemCount)
 			.max(min_items);
-		self.file.set_len(self.item_count as u64 * self.item_size as u64 + self.table_header_size as u64)
+		self.resize(items_backed);
+	}
+
+	/// The number of items for which the file currently has space.
+	fn items_backed(&self) -> TableItemCount {
+		(self.data.read().len() / self.item_size) as TableItemCount
+	}
+
+	/// Set the file size, and the amount mapped, to hold exactly `items_backed` items.
+	fn resize(&mut self, items_backed: TableItemCount) {
+		self.file.set_len(items_backed as u64 * self.item_size as u64 + self.table_header_size as u64)
 			.expect("File must be writable.");
 		*self.header_data.write() = unsafe {
 			MmapOptions::new()
@@ -249,7 +613,7 @@ impl<K: KeyType> Table<K> {
 	///
 	/// This will panic if `index >= self.item_count`.
 	fn ensure_referencable(&mut self, index: TableItemIndex) {
-		let items_backed = (self.data.read().len() / self.item_size) as TableItemCount;
+		let items_backed = self.items_backed();
 		let index = index as TableItemCount;
 		assert!(index < self.item_count, "Oversize index. WTF?");
 		if index >= items_backed {
@@ -259,10 +623,9 @@ impl<K: KeyType> Table<K> {
 
 	/// Ensures that an item's contents are (immutably) mapped. This will never mutate anything in
 	/// such a way that an existing reference becomes invalid. Specifically it is *NOT ALLOWED* to
-	/// change a `Some(MmapMut)` into a `None`, only a `None` into a `Some`. This ensures that the
-	/// unsafe function used later in `item_ref` is always safe, since it relies on those references
-	/// staying valid as long as there's no mutable reference taken to this struct. (A mutable
-	/// reference is needed in order to invalidate any of those references.)
+	/// change a `Some(MmapMut)` into a `None`, only a `None` into a `Some`. References handed out
+	/// by `item_ref` rely on this, staying valid as long as they hold a read lock on `maps`; only
+	/// `release_mapping`, while holding the write lock, or a mutable reference may unmap.
 	///
 	/// Will return `None` if `i` is not an item we currently have stored, `Some(mapped_bytes)` with
 	/// the number of bytes that has been additionally mapped (0 if it was already mapped) if it is
@@ -317,6 +680,17 @@ impl<K: KeyType> Table<K> {
 		Some(bytes)
 	}
 
+	/// Unmap the contents of item `i`, once a scan is done with it, so that scanning a table
+	/// doesn't leave all of its items mapped. If any reference to the contents of an item of this
+	/// table is still held then nothing is unmapped.
+	pub fn release_mapping(&self, i: TableItemIndex) {
+		if let Some(mut maps) = self.maps.try_write() {
+			if let Some((data, _)) = maps.get_mut(i as usize).and_then(Option::take) {
+				self.mapped.fetch_sub(data.len(), Release);
+			}
+		}
+	}
+
 	/// Reduce the number of items mapped until the total size is less than `maximum_size`.
 	pub fn shrink_to(&mut self, maximum_size: usize, shrink_size: usize) {
 		let current_size = self.mapped.load(Acquire);
@@ -342,12 +716,15 @@ impl<K: KeyType> Table<K> {
 
 	fn set_header(&mut self, h: TableHeader) {
 		self.header = h;
-		self.header.encode_to(&mut SimpleWriter(self.header_data.write().as_mut(), 0));
+		self.header.encode_to(&mut SimpleWriter(self.header_data.write().as_mut(), DESCRIPTOR_SIZE));
 	}
 
-	/// The total amount of bytes stored on disk for this table.
+	/// The total amount of bytes stored on disk for this table, its header included. Holes punched
+	/// for free slots are not counted.
 	pub fn bytes_used(&self) -> usize {
-		self.data.read().len() + self.header.external_data as usize
+		let len = self.table_header_size + self.data.read().len();
+		allocated_bytes(&self.file).map_or(len, |allocated| allocated.min(len))
+			+ self.header.external_data as usize
 	}
 
 	/// The amount of bytes currently mapped into memory for this table.
@@ -407,6 +784,7 @@ impl<K: KeyType> Table<K> {
 	/// Retrieve a table item's key hash.
 	#[allow(dead_code)]
 	pub fn item_hash(&self, i: TableItemIndex) -> Result<K, ()> {
+		if !self.allocated.get(i as usize) { return Err(()) }
 		self.item_header(i).and_then(|h| h.to_maybe_key().ok_or(()))
 	}
 
@@ -427,6 +805,16 @@ impl<K: KeyType> Table<K> {
 		})
 	}
 
+	/// Retrieve the length of a table item's data, without needing to map it.
+	pub fn item_len(&self, i: TableItemIndex) -> Result<usize, ()> {
+		let header = self.item_header(i).and_then(|h| h.as_allocation(None))?;
+		Ok(if self.value_size == 0 {
+			std::fs::metadata(self.contents_name(i)).map_err(|_| ())?.len() as usize
+		} else {
+			self.value_size - header.1
+		})
+	}
+
 	pub fn set_item(&mut self, i: TableItemIndex, data: &[u8]) -> Result<(), ()> {
 		let header = self.item_header(i)?;
 		if self.value_size == 0 {
@@ -462,41 +850,42 @@ impl<K: KeyType> Table<K> {
 				*ref_count += 1;
 				*ref_count
 			}
-			ItemHeader::Free(..) => return Err(()),
+			ItemHeader::Free => return Err(()),
 		};
 		self.set_item_header(i, item)?;
 		Ok(rc)
 	}
 
+	/// Set the number of references of a slot that is already allocated. Err if the slot is not
+	/// allocated.
+	pub fn set_ref_count(&mut self, i: TableItemIndex, ref_count: RefCount) -> Result<(), ()> {
+		self.mutate_item_header(i, |item| match item {
+			ItemHeader::Allocated { ref_count: ref mut r, .. } => { *r = ref_count; Ok(()) }
+			ItemHeader::Free => Err(()),
+		})?
+	}
+
 	/// Attempt to allocate a slot.
 	pub fn allocate(&mut self, key: &K, size: usize) -> Option<TableItemIndex> {
 		let mut h = self.header.clone();
 		let size_correction = if self.value_size > 0 { (self.value_size - size) as u32 } else { 0 };
 		// OPTIMISE: Avoid extra copy of `key` by writing directly to map.
 		let new_item = ItemHeader::Allocated { ref_count: 1, size_correction, key: key.clone() };
-		let result = if h.used < h.touched_count {
-			let result = h.next_free;
-			let new_next_free = self.mutate_item_header(result, |item| {
-				let new_next_free = item.as_next_free();
-				*item = new_item;
-				new_next_free
-			}).ok()?;
-			h.next_free = new_next_free;
-			result
-		} else {
-			if h.touched_count < self.item_count {
+		let result = match self.allocated.next_free(h.touched_count as usize) {
+			Some(free) => free as TableItemIndex,
+			None if h.touched_count < self.item_count => {
 				let result = h.touched_count as TableItemIndex;
 				self.ensure_referencable(result);
-				self.mutate_item_header(result, |item| {
-					assert!(matches!(item, ItemHeader::Free(_)), "Free slot expected. Database corrupt?");
-					*item = new_item;
-				}).ok()?;
 				h.touched_count += 1;
 				result
-			} else {
-				return None
 			}
+			None => return None,
 		};
+		self.mutate_item_header(result, |item| {
+			assert!(matches!(item, ItemHeader::Free), "Free slot expected. Database corrupt?");
+			*item = new_item;
+		}).ok()?;
+		self.mark(result, true);
 		h.used += 1;
 		if self.value_size == 0 {
 			h.external_data += size as u64;
@@ -504,7 +893,7 @@ impl<K: KeyType> Table<K> {
 		self.set_header(h);
 		let maps = self.maps.upgradable_read();
 		if maps.len() <= result as usize {
-			let new_len = (result as usize * 3 / 2).max(self.item_count as usize);
+			let new_len = ((result as usize + 1) * 3 / 2).min(self.item_count as usize);
 			RwLockUpgradableReadGuard::upgrade(maps).resize_with(new_len, || None);
 		}
 		Some(result)
@@ -513,6 +902,9 @@ impl<K: KeyType> Table<K> {
 	/// Free up a slot or decrease the reference count if it's greater than 1. Returns Ok along with
 	/// the number of refs remaining, or Err if the slot was already free.
 	pub fn free(&mut self, i: TableItemIndex, check_hash: Option<&K>) -> Result<RefCount, ()> {
+		if !self.allocated.get(i as usize) {
+			return Err(())
+		}
 		let mut h = self.header.clone();
 		let result = self.mutate_item_header(i, |item| {
 			match item {
@@ -524,11 +916,10 @@ impl<K: KeyType> Table<K> {
 						return Ok(*ref_count)
 					}
 				}
-				ItemHeader::Free(..) => return Err(()),
+				ItemHeader::Free => panic!("Allocated slot is free. Database corrupt?"),
 			}
-			// Stich the old free list head onto this item.
-			*item = ItemHeader::Free(h.next_free);
-			Ok(0)
+			*item = ItemHeader::Free;
+			Ok::<_, ()>(0)
 		})??;
 		if result == 0 {
 			if self.value_size == 0 {
@@ -541,21 +932,142 @@ impl<K: KeyType> Table<K> {
 				h.external_data = h.external_data.checked_sub(size)
 					.expect("external_data underflow. Database corruption?");
 			}
-			// Add the item to the free list.
+			self.mark(i, false);
+			h.freed_since_punch = h.freed_since_punch.saturating_add(1);
+			h.changes += 1;
 			h.used = h.used.checked_sub(1)
 				.expect("Database corrupt? used count underflow");
-			h.next_free = i;
 			self.set_header(h);
 		}
 		Ok(result)
 	}
 
+	/// Free up a slot entirely, regardless of how many references it has. Err if the slot was
+	/// already free.
+	pub fn release(&mut self, i: TableItemIndex) -> Result<(), ()> {
+		self.mutate_item_header(i, |item| match item {
+			ItemHeader::Allocated { ref mut ref_count, .. } => { *ref_count = 1; Ok(()) }
+			ItemHeader::Free => Err(()),
+		})??;
+		self.free(i, None).map(|_| ())
+	}
+
+	/// The indices of all slots which are currently allocated, in order.
+	pub fn allocated(&self) -> Vec<TableItemIndex> {
+		self.allocated.iter_set(self.header.touched_count as usize)
+			.map(|i| i as TableItemIndex)
+			.collect()
+	}
+
+	/// The index of the first allocated slot at or after `from`, if there is one.
+	pub fn next_allocated(&self, from: TableItemIndex) -> Option<TableItemIndex> {
+		self.allocated.next_set(from as usize, self.header.touched_count as usize)
+			.map(|i| i as TableItemIndex)
+	}
+
+	/// The raw bytes of the slot at `i`; its header and its value, padding included. Only
+	/// meaningful for sized tables.
+	pub fn raw_item(&self, i: TableItemIndex) -> Vec<u8> {
+		assert!(self.value_size > 0, "Raw items are only for sized tables");
+		let offset = self.item_size * i as usize;
+		self.data.read()[offset..offset + self.item_size].to_vec()
+	}
+
+	/// Allocate a slot and fill it with `raw`, as returned by `raw_item` from a table of the same
+	/// size class. Returns the new slot's index along with the item's key.
+	pub fn allocate_raw(&mut self, raw: &[u8]) -> Option<(TableItemIndex, K)> {
+		assert_eq!(raw.len(), self.item_size, "Raw item from a different size class");
+		let (key, size) = match ItemHeader::<K>::decode(&mut &raw[..], self.correction_factor).ok()? {
+			ItemHeader::Allocated { key, size_correction, .. } => (key, self.value_size - size_correction as usize),
+			ItemHeader::Free => return None,
+		};
+		let i = self.allocate(&key, size)?;
+		let offset = self.item_size * i as usize;
+		self.data.write()[offset..offset + self.item_size].copy_from_slice(raw);
+		let mut h = self.header;
+		h.changes += 1;
+		self.set_header(h);
+		Some((i, key))
+	}
+
+	/// Move all allocated items into the lowest slots, calling `relocated` for each item moved, and
+	/// then shrink the file to fit them, leaving space for at least `min_items_backed` items. Only
+	/// sized tables are compacted; oversize items already give back their space when freed.
+	pub fn compact(
+		&mut self,
+		min_items_backed: TableItemCount,
+		mut relocated: impl FnMut(&K, TableItemIndex, TableItemIndex),
+	) {
+		if self.value_size == 0 {
+			return
+		}
+		let used = self.header.used;
+		let holes = self.allocated.iter_free(used as usize).collect::<Vec<_>>();
+		let movers = self.allocated.iter_set(self.header.touched_count as usize)
+			.skip_while(|&i| i < used as usize)
+			.collect::<Vec<_>>();
+		debug_assert_eq!(holes.len(), movers.len());
+		let moved = movers.len() as u64;
+		for (to, from) in holes.into_iter().zip(movers) {
+			let (to, from) = (to as TableItemIndex, from as TableItemIndex);
+			let key = self.item_hash(from).expect("Allocated in bitmap. Database corrupt?");
+			let raw = self.raw_item(from);
+			let offset = self.item_size * to as usize;
+			self.data.write()[offset..offset + self.item_size].copy_from_slice(&raw);
+			self.mark(to, true);
+			self.set_item_header(from, ItemHeader::Free).expect("Index within table");
+			self.mark(from, false);
+			relocated(&key, from, to);
+		}
+		// All items are now in the lowest `used` slots.
+		let mut h = self.header;
+		h.touched_count = used;
+		h.changes += moved;
+		self.set_header(h);
+
+		let items_backed = used.max(min_items_backed.min(self.item_count));
+		if items_backed < self.items_backed() {
+			self.resize(items_backed);
+		}
+	}
+
+	/// Check that the bitmap agrees with the item headers and the table header, returning a
+	/// description of each inconsistency. A slot marked free whose header is allocated is an item
+	/// which may be overwritten; a slot marked allocated whose header is free has been freed twice
+	/// or never written.
+	pub fn check(&self) -> Vec<String> {
+		check_slots(&self.path, &self.header, &self.allocated, |i| self.item_header(i).ok())
+	}
+
+	/// Delete the table's file. It must be empty.
+	pub fn delete(self) {
+		assert_eq!(self.header.used, 0, "Only empty tables may be deleted");
+		let path = self.path.clone();
+		drop(self);
+		std::fs::remove_file(path).expect("cannot remove table file. Permissions wrong?");
+	}
+
+	/// The length of the header of each item: its reference count, size correction and key.
+	pub fn item_header_size(&self) -> usize {
+		self.item_header_size
+	}
+
+	/// The length of the value of each item, or zero if the items are oversize.
+	pub fn value_size(&self) -> usize {
+		self.value_size
+	}
+
 	/// The amount of slots that are occupied with data in this table.
 	#[allow(dead_code)]
 	pub fn used(&self) -> TableItemCount {
 		self.header.used
 	}
 
+	/// How many times an item has been freed from the table or moved into a slot of it.
+	pub fn changes(&self) -> u64 {
+		self.header.changes
+	}
+
 	/// The total number of items that this table could ever hold at once.
 	#[allow(dead_code)]
 	pub fn total(&self) -> TableItemCount {
@@ -569,6 +1081,175 @@ impl<K: KeyType> Table<K> {
 	}
 }
 
+/// An item read from a `RawTable`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RawItem {
+	/// Number of times this item has been inserted, without a corresponding remove.
+	pub ref_count: RefCount,
+	pub key: Vec<u8>,
+	pub value: Vec<u8>,
+}
+
+/// A content table opened only for reading, whose key length is taken from its descriptor rather
+/// than from a key type.
+pub struct RawTable {
+	path: PathBuf,
+	file: File,
+	descriptor: TableDescriptor,
+	header: TableHeader,
+	allocated: FreeMap,
+	correction_factor: CorrectionFactor,
+	item_header_size: usize,
+	item_size: usize,
+	table_header_size: usize,
+	data: Mmap,
+}
+
+impl RawTable {
+	/// Open the existing table at `path` without ever writing to it.
+	pub fn open(path: PathBuf) -> Result<Self, Error> {
+		let file = File::open(&path)?;
+		let invalid = |what: &str| Error::IncompatibleTable(format!("{}: {}", path.display(), what));
+		let data = unsafe { Mmap::map(&file)? };
+		let descriptor = match TableDescriptor::decode(&mut data.as_ref()) {
+			Ok(d) if d.magic == TABLE_MAGIC => d,
+			_ => return Err(invalid("not a content table")),
+		};
+		if descriptor.version != TABLE_VERSION {
+			return Err(invalid("unsupported format version"))
+		}
+		let table_header_size = bitmap_offset() + bitmap_size(descriptor.item_count);
+		if data.len() < table_header_size {
+			return Err(invalid("too short to be a content table"))
+		}
+		let header = TableHeader::decode(&mut &data[DESCRIPTOR_SIZE..])
+			.map_err(|_| invalid("bad header"))?;
+		let allocated = FreeMap::new(data[bitmap_offset()..table_header_size]
+			.chunks(8)
+			.map(|w| u64::from_le_bytes(w.try_into().expect("chunks of 8; qed")))
+			.collect()
+		);
+		let correction_factor = CorrectionFactor::from_size(descriptor.correction_factor_size)
+			.ok_or_else(|| invalid("bad size correction length"))?;
+		let item_header_size = size_of::<RefCount>()
+			+ descriptor.correction_factor_size as usize
+			+ descriptor.key_size as usize;
+		let item_size = item_header_size + descriptor.value_size as usize;
+		Ok(Self { path, file, descriptor, header, allocated, correction_factor, item_header_size, item_size, table_header_size, data })
+	}
+
+	/// The size class of the table's items.
+	pub fn datum_size(&self) -> DatumSize {
+		DatumSize::from(self.descriptor.size_class)
+	}
+
+	/// The length of the key of each item.
+	pub fn key_size(&self) -> usize {
+		self.descriptor.key_size as usize
+	}
+
+	/// The length of the value of each item, or zero if the items are oversize.
+	pub fn value_size(&self) -> usize {
+		self.descriptor.value_size as usize
+	}
+
+	/// The total number of items that this table could ever hold at once.
+	pub fn total(&self) -> TableItemCount {
+		self.descriptor.item_count
+	}
+
+	/// The amount of slots that are occupied with data in this table.
+	pub fn used(&self) -> TableItemCount {
+		self.header.used
+	}
+
+	/// The number of slots that have ever been allocated.
+	pub fn touched(&self) -> TableItemCount {
+		self.header.touched_count
+	}
+
+	/// The length of the header of each item: its reference count, size correction and key.
+	pub fn item_header_size(&self) -> usize {
+		self.item_header_size
+	}
+
+	/// The amount of bytes stored on disk, as for `Table::bytes_used`.
+	pub fn bytes_used(&self) -> usize {
+		allocated_bytes(&self.file).map_or(self.data.len(), |allocated| allocated.min(self.data.len()))
+			+ self.header.external_data as usize
+	}
+
+	/// The indices of all slots which are currently allocated, in order.
+	pub fn allocated(&self) -> Vec<TableItemIndex> {
+		self.allocated.iter_set(self.header.touched_count as usize)
+			.map(|i| i as TableItemIndex)
+			.collect()
+	}
+
+	/// The header of the item in slot `i`, if the slot is in the file.
+	fn item_header(&self, i: TableItemIndex) -> Option<ItemHeader<Vec<u8>>> {
+		let offset = self.table_header_size + self.item_size * i as usize;
+		let mut item = self.data.get(offset..offset + self.item_header_size)?;
+		ItemHeader::decode_into(&mut item, self.correction_factor, vec![0u8; self.key_size()]).ok()
+	}
+
+	/// The name of the file holding the value of the oversize item in slot `i`.
+	fn contents_name(&self, i: TableItemIndex) -> PathBuf {
+		let mut path = self.path.clone();
+		path.set_extension(format!("{}", i));
+		path
+	}
+
+	/// The item in slot `i`, if it is allocated.
+	pub fn item(&self, i: TableItemIndex) -> Option<RawItem> {
+		if !self.allocated.get(i as usize) {
+			return None
+		}
+		let (ref_count, size_correction, key) = match self.item_header(i)? {
+			ItemHeader::Allocated { ref_count, size_correction, key } => (ref_count, size_correction as usize, key),
+			ItemHeader::Free => return None,
+		};
+		let value = if self.descriptor.value_size == 0 {
+			std::fs::read(self.contents_name(i)).ok()?
+		} else {
+			let size = (self.descriptor.value_size as usize).checked_sub(size_correction)?;
+			let offset = self.table_header_size + self.item_size * i as usize + self.item_header_size;
+			self.data.get(offset..offset + size)?.to_vec()
+		};
+		Some(RawItem { ref_count, key, value })
+	}
+
+	/// The key of the item in slot `i`, if it is allocated.
+	pub fn item_key(&self, i: TableItemIndex) -> Option<Vec<u8>> {
+		if !self.allocated.get(i as usize) {
+			return None
+		}
+		match self.item_header(i)? {
+			ItemHeader::Allocated { key, .. } => Some(key),
+			ItemHeader::Free => None,
+		}
+	}
+
+	/// The length of the value of the item in slot `i`, if it is allocated.
+	pub fn item_len(&self, i: TableItemIndex) -> Option<usize> {
+		if !self.allocated.get(i as usize) {
+			return None
+		}
+		match self.item_header(i)? {
+			ItemHeader::Allocated { .. } if self.descriptor.value_size == 0 =>
+				std::fs::metadata(self.contents_name(i)).ok().map(|m| m.len() as usize),
+			ItemHeader::Allocated { size_correction, .. } =>
+				(self.descriptor.value_size as usize).checked_sub(size_correction as usize),
+			ItemHeader::Free => None,
+		}
+	}
+
+	/// Check the table for consistency, as for `Table::check`.
+	pub fn check(&self) -> Vec<String> {
+		check_slots(&self.path, &self.header, &self.allocated, |i| self.item_header(i))
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -577,31 +1258,33 @@ mod tests {
 	#[test]
 	fn database_should_work() {
 		let path = PathBuf::from("/tmp/test-table-database_should_work");
+		let _ = std::fs::remove_file(&path);
 		let x = {
-			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), 65536);
+			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 65536).unwrap();
 			let x = t.allocate(&[42u8], 12).unwrap();
 			t.set_item(x, b"Hello world!").unwrap();
 			assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 			t.commit();
 			x
 		};
-		let t = Table::<[u8; 1]>::open(path.clone(), 0.into(), 65536);
+		let t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 65536).unwrap();
 		assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 	}
 
 	#[test]
 	fn thin_table_should_work() {
 		let path = PathBuf::from("/tmp/test-table-thin_table_should_work");
+		let _ = std::fs::remove_file(&path);
 		for i in 0..10 { let _ = std::fs::remove_file(format!("/tmp/test-table.{}", i)); }
 		let x = {
-			let mut t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, 65536);
+			let mut t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, &SizeScheme::default(), 65536).unwrap();
 			let x = t.allocate(&[42u8], 12).unwrap();
 			t.set_item(x, b"Hello world!").unwrap();
 			assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 			t.commit();
 			x
 		};
-		let t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, 65536);
+		let t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, &SizeScheme::default(), 65536).unwrap();
 		assert_eq!(t.item_ref(x, Some(&[42u8])).unwrap().as_ref(), b"Hello world!");
 	}
 
@@ -610,16 +1293,16 @@ mod tests {
 		let path = PathBuf::from("/tmp/test-table-table_extension_should_work");
 		let _ = std::fs::remove_file(&path);
 		let x = {
-			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), 0);
-			assert_eq!(t.bytes_used(), 0);
+			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 0).unwrap();
+			let before = t.bytes_used();
 			let x = t.allocate(&[42u8], 12).unwrap();
 			t.set_item(x, b"Hello world!").unwrap();
-			assert_eq!(t.bytes_used(), 36);
+			assert!(t.bytes_used() >= before + 36);
 			assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 			t.commit();
 			x
 		};
-		let t = Table::<[u8; 1]>::open(path.clone(), 0.into(), 0);
+		let t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 0).unwrap();
 		assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 	}
 
@@ -629,16 +1312,169 @@ mod tests {
 		let _ = std::fs::remove_file(&path);
 		for i in 0..10 { let _ = std::fs::remove_file(format!("/tmp/test-table.{}", i)); }
 		let x = {
-			let mut t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, 0);
-			assert_eq!(t.bytes_used(), 0);
+			let mut t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, &SizeScheme::default(), 0).unwrap();
+			let before = t.bytes_used();
 			let x = t.allocate(&[42u8], 12).unwrap();
 			t.set_item(x, b"Hello world!").unwrap();
-			assert_eq!(t.bytes_used(), 15);
+			assert!(t.bytes_used() >= before + 12);
 			assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 			t.commit();
 			x
 		};
-		let t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, 0);
+		let t = Table::<[u8; 1]>::open(path.clone(), DatumSize::Oversize, &SizeScheme::default(), 0).unwrap();
 		assert_eq!(t.item_ref(x, None).unwrap().as_ref(), b"Hello world!");
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn incompatible_table_is_refused() {
+		let path = PathBuf::from("/tmp/test-table-incompatible_table_is_refused");
+		let _ = std::fs::remove_file(&path);
+		let scheme = SizeScheme::default();
+		Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).unwrap();
+		assert!(Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).is_ok());
+
+		fn err<K>(r: Result<Table<K>, Error>) -> String {
+			match r {
+				Err(Error::IncompatibleTable(s)) => s,
+				_ => panic!("Table should be incompatible"),
+			}
+		}
+		assert!(err(Table::<[u8; 2]>::open(path.clone(), 0.into(), &scheme, 0)).ends_with("key length is 1 but expected 2"));
+		assert!(err(Table::<[u8; 1]>::open(path.clone(), 1.into(), &scheme, 0)).ends_with("size class is 0 but expected 1"));
+		let small = SizeScheme::small_values();
+		assert!(err(Table::<[u8; 1]>::open(path.clone(), 0.into(), &small, 0)).ends_with("value size is 32 but expected 8"));
+
+		std::fs::write(&path, vec![42u8; 65536]).unwrap();
+		assert!(err(Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0)).ends_with("not a content table"));
+		std::fs::write(&path, b"SBDT").unwrap();
+		assert!(err(Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0)).ends_with("too short to be a content table"));
+	}
+
+	#[test]
+	fn unversioned_table_is_migrated() {
+		let path = PathBuf::from("/tmp/test-table-unversioned_table_is_migrated");
+		let _ = std::fs::remove_file(&path);
+		// Laid out as before tables described themselves: the header, then 36 byte items of a
+		// two byte reference count, a byte of size correction, the key and the value. Slot 1 is
+		// free and heads the free list.
+		let mut data = UnversionedTableHeader { used: 2, next_free: 1, touched_count: 3, external_data: 0 }.encode();
+		data.resize(UNVERSIONED_HEADER_SIZE, 0);
+		let item = |ref_count: u8, key: u8, value: &[u8]| {
+			let mut item = vec![0x80, ref_count, 32 - value.len() as u8, key];
+			item.extend_from_slice(value);
+			item.resize(36, 0);
+			item
+		};
+		data.extend(item(1, 1, b"Hello"));
+		data.extend(vec![0u8; 36]);
+		data.extend(item(2, 3, b"world!"));
+		data.resize(UNVERSIONED_HEADER_SIZE + 36 * 4, 0);
+		std::fs::write(&path, data).unwrap();
+
+		let scheme = SizeScheme::default();
+		{
+			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).unwrap();
+			assert!(t.check().is_empty());
+			assert_eq!(t.used(), 2);
+			assert_eq!(t.allocated(), vec![0, 2]);
+			assert_eq!(t.item_ref(0, Some(&[1])).unwrap().as_ref(), b"Hello");
+			assert_eq!(t.item_ref(2, Some(&[3])).unwrap().as_ref(), b"world!");
+			assert_eq!(t.item_ref_count(2, None), Ok(2));
+			assert_eq!(t.allocate(&[4], 12), Some(1));
+			t.commit();
+		}
+		let t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).unwrap();
+		assert_eq!(t.allocated(), vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn freed_slots_should_be_punched() {
+		let path = PathBuf::from("/tmp/test-table-freed_slots_should_be_punched");
+		let _ = std::fs::remove_file(&path);
+		let value = |i: u8| vec![i; 4096];
+		let scheme = SizeScheme::default();
+		let mut t = Table::<[u8; 1]>::open(path.clone(), scheme.nearest(4096), &scheme, 64).unwrap();
+		let items = (0..64).map(|i| {
+			let x = t.allocate(&[i], 4096).unwrap();
+			t.set_item(x, &value(i)).unwrap();
+			x
+		}).collect::<Vec<_>>();
+		t.commit();
+		let before = t.bytes_used();
+
+		for &x in &items[8..56] {
+			t.free(x, None).unwrap();
+		}
+		t.commit();
+		if cfg!(target_os = "linux") {
+			assert!(t.bytes_used() <= before - 40 * 4096);
+		}
+
+		// The free list must have survived and the other items must be untouched.
+		for &x in items[..8].iter().chain(items[56..].iter()) {
+			assert_eq!(t.item_ref(x, Some(&[x as u8])).unwrap().as_ref(), &value(x as u8)[..]);
+		}
+		let mut reallocated = (0..48).map(|_| t.allocate(&[0], 4096).unwrap()).collect::<Vec<_>>();
+		reallocated.sort();
+		assert_eq!(&reallocated[..], &items[8..56]);
+		assert_eq!(t.allocate(&[0], 4096), Some(64));
+	}
+
+	#[test]
+	fn frees_before_reopening_should_count_towards_punching() {
+		let path = PathBuf::from("/tmp/test-table-frees_before_reopening_should_count_towards_punching");
+		let _ = std::fs::remove_file(&path);
+		let scheme = SizeScheme::default();
+		let before = {
+			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).unwrap();
+			for i in 0..400 {
+				t.allocate(&[i as u8], 12).unwrap();
+			}
+			t.commit();
+			let before = t.bytes_used();
+			// Not enough to cover a page on its own.
+			for x in 50..150 {
+				t.free(x, None).unwrap();
+			}
+			t.commit();
+			assert_eq!(t.bytes_used(), before);
+			before
+		};
+		let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &scheme, 0).unwrap();
+		for x in 150..250 {
+			t.free(x, None).unwrap();
+		}
+		t.commit();
+		if cfg!(target_os = "linux") {
+			assert!(t.bytes_used() < before);
+		}
+	}
+
+	#[test]
+	fn allocation_bitmap_should_persist() {
+		let path = PathBuf::from("/tmp/test-table-allocation_bitmap_should_persist");
+		let _ = std::fs::remove_file(&path);
+		{
+			let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 0).unwrap();
+			let items = (0..100).map(|i| t.allocate(&[i], 12).unwrap()).collect::<Vec<_>>();
+			for &x in items.iter().filter(|&&x| x % 3 == 0) {
+				t.free(x, None).unwrap();
+			}
+			// A double-free is refused.
+			assert_eq!(t.free(3, None), Err(()));
+			assert!(t.check().is_empty());
+			t.commit();
+		}
+		let mut t = Table::<[u8; 1]>::open(path.clone(), 0.into(), &SizeScheme::default(), 0).unwrap();
+		assert!(t.check().is_empty());
+		assert_eq!(t.used(), 66);
+		assert_eq!(t.allocated().len(), 66);
+		// Free slots are reused lowest first.
+		assert_eq!(t.allocate(&[0], 12), Some(0));
+		assert_eq!(t.allocate(&[0], 12), Some(3));
+
+		// Corruption is spotted.
+		t.set_item_header(3, ItemHeader::Free).unwrap();
+		assert_eq!(t.check().len(), 1);
+	}
+}
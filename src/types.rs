@@ -11,6 +11,12 @@ impl<
 	T: AsRef<[u8]> + AsMut<[u8]> + Default + Eq + PartialEq + Clone + Debug + Send + Sync
 > KeyType for T {}
 
+/// The length of the keys of type `K`. This is taken from a default key rather than the size of
+/// the type, so that keys may be of a length only known at runtime.
+pub(crate) fn key_length<K: KeyType>() -> usize {
+	K::default().as_ref().len()
+}
+
 pub trait EncodedSize: Encode {
 	fn encoded_size() -> usize;
 }